@@ -1,125 +1,289 @@
-pub mod proto;
-
-use crate::proto::{PerfRequest, WorkType};
-use argparse::{ArgumentParser, Store, StoreTrue};
+use crate::engine;
+use crate::proto::{
+    self, IntervalResult, PerfRequest, PerfResponse, PerfResults, TestLength, WorkType,
+    DEFAULT_PORT, PROTOCOL_VERSION,
+};
+use crate::report::{self, IntervalReporter, StreamCounters, StreamReport, TestReport};
+use crate::transport::kcp::{KcpConfig, KcpTransport};
+use crate::transport::smoltcp::{SmoltcpTcpConfig, SmoltcpTcpTransport, SmoltcpUdpTransport};
+use crate::transport::stack::Stack;
+use crate::transport::tcp::TcpTransport;
+use crate::transport::udp::UdpTransport;
+use crate::transport::{Transport, TransportKind, TransportOptions};
+use pbr::MultiBar;
 use std::io;
-use std::io::{Read, Write};
-use std::net::{Shutdown, TcpStream};
-use std::time::{Duration, Instant, SystemTime};
-use pbr::{ProgressBar, MultiBar};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub fn nonblocking_write_all(stream: &mut std::net::TcpStream, mut buf: &[u8]) -> io::Result<()> {
-    while !buf.is_empty() {
-        match stream.write(buf) {
-            Ok(0) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::WriteZero,
-                    "failed to write whole buffer",
-                ));
-            }
-            Ok(n) => buf = &buf[n..],
-            Err(ref e)
-                if e.kind() == io::ErrorKind::Interrupted
-                    || e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(e) => return Err(e),
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub transport: TransportKind,
+    pub work_type: WorkType,
+    /// Control address of the server.
+    pub address: SocketAddr,
+    pub bucket_size: usize,
+    pub length: TestLength,
+    pub nstreams: usize,
+    /// Payload bits per second each sender is paced to, unlimited if `None`.
+    pub bitrate: Option<u64>,
+    /// Also cap TCP streams with the kernel's `SO_MAX_PACING_RATE`.
+    pub kernel_pacing: bool,
+    pub kcp: KcpConfig,
+    pub smoltcp_tcp: SmoltcpTcpConfig,
+    /// User-space stack the smoltcp transports run on.
+    pub stack: Option<Arc<Stack>>,
+    /// Report per-interval throughput this often instead of progress bars.
+    pub interval: Option<Duration>,
+    /// Keep stdout free for the caller, e.g. for a `--json` document: no
+    /// progress bars and no interval lines, the intervals only end up in the
+    /// returned report.
+    pub quiet: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            transport: TransportKind::Tcp,
+            work_type: WorkType::Send,
+            address: SocketAddr::new([127, 0, 0, 1].into(), DEFAULT_PORT),
+            bucket_size: TransportKind::Tcp.default_bucket_size(),
+            length: TestLength::Repeat(10000),
+            nstreams: 1,
+            bitrate: None,
+            kernel_pacing: false,
+            kcp: KcpConfig::default(),
+            smoltcp_tcp: SmoltcpTcpConfig::default(),
+            stack: None,
+            interval: None,
+            quiet: false,
         }
-        std::thread::yield_now();
     }
-    Ok(())
 }
 
-pub fn nonblocking_read_exact(
-    stream: &mut std::net::TcpStream,
-    mut buf: &mut [u8],
-) -> io::Result<()> {
-    while !buf.is_empty() {
-        match stream.read(buf) {
-            Ok(0) => break,
-            Ok(n) => {
-                let tmp = buf;
-                buf = &mut tmp[n..];
-            }
-            Err(ref e)
-                if e.kind() == io::ErrorKind::Interrupted
-                    || e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(e) => return Err(e),
+impl ClientConfig {
+    pub fn perf_request(&self) -> PerfRequest {
+        PerfRequest {
+            version: PROTOCOL_VERSION,
+            work_type: self.work_type,
+            transport: self.transport,
+            bucket_size: self.bucket_size,
+            length: self.length,
+            nstreams: self.nstreams,
+            bitrate: self.bitrate,
+            kernel_pacing: self.kernel_pacing,
+            kcp: self.kcp,
+            smoltcp_tcp: self.smoltcp_tcp,
         }
-        std::thread::yield_now();
-    }
-    if !buf.is_empty() {
-        Err(io::Error::new(
-            io::ErrorKind::UnexpectedEof,
-            "failed to fill whole buffer",
-        ))
-    } else {
-        Ok(())
     }
 }
 
-fn main() {
-    let mut address = "127.0.0.1:63590".to_string();
-    let mut bucket_size: usize = 1 * (1024 as usize).pow(2);
-    let mut repeat = 10000;
-    let mut nstreams = 1;
-    {
-        // this block limits scope of borrows by ap.refer() method
-        let mut ap = ArgumentParser::new();
-        ap.set_description("tcp server.");
-        ap.refer(&mut address)
-            .add_option(&["--server_address"], Store, "Server address");
-        ap.refer(&mut bucket_size)
-            .add_option(&["--bucket_size"], Store, "Bucket size");
-        ap.refer(&mut repeat)
-            .add_option(&["--repeat"], Store, "repeat count");
-        ap.refer(&mut nstreams)
-            .add_option(&["--nstreams"], Store, "num of stream");
-        ap.parse_args_or_exit();
-    }
+/// Announces the test to the server over a control connection, then connects
+/// `config.nstreams` streams and moves buckets on each for `config.length` in
+/// the direction given by `config.work_type`. Returns the reports of both ends.
+pub fn run(config: &ClientConfig) -> io::Result<TestReport> {
+    let mut control = TcpStream::connect(config.address).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("failed to connect to {}: {}", config.address, err),
+        )
+    })?;
+    proto::write_message(&mut control, &config.perf_request())?;
+    let (data_addr, kcp_conv) = match proto::read_message(&mut control)? {
+        PerfResponse::Accept {
+            data_addr,
+            kcp_conv,
+        } if data_addr.ip().is_unspecified() => (
+            SocketAddr::new(config.address.ip(), data_addr.port()),
+            kcp_conv,
+        ),
+        PerfResponse::Accept {
+            data_addr,
+            kcp_conv,
+        } => (data_addr, kcp_conv),
+        PerfResponse::Reject { reason } => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("server rejected the test: {}", reason),
+            ));
+        }
+    };
+
+    let options = TransportOptions {
+        bucket_size: config.bucket_size,
+        kcp_conv,
+        kcp_nconvs: config.nstreams,
+        kcp: config.kcp,
+        pacing_rate: config.perf_request().pacing_rate(),
+        smoltcp_tcp: config.smoltcp_tcp,
+        stack: config.stack.clone(),
+        accept_timeout: None,
+        peer_timeout: None,
+    };
+    let (client, intervals) = match config.transport {
+        TransportKind::Tcp => run_streams::<TcpTransport>(config, &data_addr, &options)?,
+        TransportKind::Kcp => run_streams::<KcpTransport>(config, &data_addr, &options)?,
+        TransportKind::SmoltcpUdp => {
+            run_streams::<SmoltcpUdpTransport>(config, &data_addr, &options)?
+        }
+        TransportKind::Udp => run_streams::<UdpTransport>(config, &data_addr, &options)?,
+        TransportKind::SmoltcpTcp => {
+            run_streams::<SmoltcpTcpTransport>(config, &data_addr, &options)?
+        }
+    };
+    let results: PerfResults = proto::read_message(&mut control)?;
 
+    Ok(TestReport {
+        client,
+        server: results.streams,
+        intervals,
+    })
+}
+
+fn run_streams<T: Transport + 'static>(
+    config: &ClientConfig,
+    data_addr: &SocketAddr,
+    options: &TransportOptions,
+) -> io::Result<(Vec<StreamReport>, Vec<IntervalResult>)> {
+    let start = Instant::now();
     let multi_bar = MultiBar::new();
+    let stream_options = |index: usize| TransportOptions {
+        kcp_conv: options.kcp_conv.wrapping_add(index as u32),
+        ..options.clone()
+    };
+    // Every stream connects before any starts, so that a failed connect
+    // leaves no worker running. Connection rate streams connect once per
+    // request instead.
+    let mut transports = Vec::new();
+    for index in 0..config.nstreams {
+        transports.push(match config.work_type {
+            WorkType::Connect { .. } => None,
+            _ => Some(T::connect(data_addr, &stream_options(index))?),
+        });
+    }
+
     let mut workers = Vec::new();
-    let server_address = address.clone();
-    for _ in 0..nstreams {
-        match TcpStream::connect(server_address.clone()) {
-            Ok(mut stream) => {
-                // stream.set_nodelay(true).unwrap();
-                // stream.set_nonblocking(true).unwrap();
-                let bucket: Vec<u8> = vec![0; bucket_size];
-                let mut progress = multi_bar.create_bar(repeat);
+    let mut streams = Vec::new();
+    for (index, transport) in transports.into_iter().enumerate() {
+        let options = stream_options(index);
+        let data_addr = *data_addr;
+        let mut bucket: Vec<u8> = vec![0; config.bucket_size];
+        let work_type = config.work_type;
+        let length = config.length;
+        let bitrate = config.bitrate;
+        // Repeat-based tests count buckets, time-based tests count seconds.
+        let total = match length {
+            TestLength::Repeat(repeat) => repeat as u64,
+            TestLength::Time(duration) => duration.as_secs().max(1),
+        };
+        let mut progress = if config.interval.is_none() && !config.quiet {
+            Some(multi_bar.create_bar(total))
+        } else {
+            None
+        };
+        let counters = Arc::new(StreamCounters::default());
+        streams.push(counters.clone());
 
-                workers.push(std::thread::spawn(move || {
-                    let now = Instant::now();
-                    let mut send_nbytes: usize = 0;
-                    for _ in 0..repeat {
-                        let target_nbytes = bucket_size.to_be_bytes();
-                        stream.write_all(&target_nbytes[..]).unwrap();
-                        // nonblocking_write_all(&mut stream, &target_nbytes[..]).unwrap();
-                        stream.write_all(&bucket[..bucket_size]).unwrap();
-                        // nonblocking_write_all(&mut stream, &bucket[..bucket_size]).unwrap();
-        
-                        send_nbytes += bucket_size;
-                        progress.inc();
-                    }
-                    progress.finish();
-        
-                    stream.write_all(&(0 as usize).to_be_bytes()[..]).unwrap();
-        
-                    println!("now.elapsed().as_secs_f64()={}", now.elapsed().as_secs_f64());
-                    let total_ngbs = send_nbytes as f64 / (1024. as f64).powf(3.);
-                    println!(
-                        "speed={}, it will be shutdown!",
-                        total_ngbs / now.elapsed().as_secs_f64()
-                    );
-                }));
+        workers.push(std::thread::spawn(move || {
+            let on_bucket = || {
+                if let Some(progress) = progress.as_mut() {
+                    match length {
+                        TestLength::Repeat(_) => progress.inc(),
+                        TestLength::Time(_) => progress.set(start.elapsed().as_secs()),
+                    };
+                }
+            };
+            let report = match transport {
+                Some(transport) => engine::run_stream(
+                    Box::new(transport),
+                    work_type,
+                    &mut bucket[..],
+                    length,
+                    bitrate,
+                    counters,
+                    on_bucket,
+                ),
+                None => engine::connect_requests(
+                    || T::connect(&data_addr, &options),
+                    &engine::indexed_request(index, &bucket[..]),
+                    &mut vec![0; work_type.response_size().unwrap_or(0)][..],
+                    length,
+                    bitrate,
+                    &counters,
+                    on_bucket,
+                ),
+            };
+            if let Some(progress) = progress.as_mut() {
+                progress.finish();
             }
-            Err(err) => {
-                println!("Failed to connect: {}", err);
-            }
-        }
+            report
+        }));
     }
-    multi_bar.listen();
 
-    for worker in workers {
-        worker.join().unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let reporter = match config.interval {
+        Some(interval) => {
+            let reporter = IntervalReporter::new(streams, start);
+            let done = done.clone();
+            let work_type = config.work_type;
+            let quiet = config.quiet;
+            Some(std::thread::spawn(move || {
+                report_intervals(reporter, start + interval, interval, &done, |result| {
+                    if !quiet {
+                        report::print_interval(result, work_type);
+                    }
+                })
+            }))
+        }
+        None => {
+            multi_bar.listen();
+            None
+        }
+    };
+
+    let results = engine::join_all(workers);
+    done.store(true, Ordering::Relaxed);
+    let intervals = match reporter {
+        Some(reporter) => reporter
+            .join()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "interval reporter panicked"))?,
+        None => Vec::new(),
+    };
+    Ok((results?, intervals))
+}
+
+/// Takes a report at `next` and every `interval` after it until `done` is
+/// set, then one last report for the partial interval at the end of the test.
+/// Every report is passed to `on_interval` and returned.
+fn report_intervals<F>(
+    mut reporter: IntervalReporter,
+    mut next: Instant,
+    interval: Duration,
+    done: &AtomicBool,
+    mut on_interval: F,
+) -> Vec<IntervalResult>
+where
+    F: FnMut(&IntervalResult),
+{
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    let mut intervals = Vec::new();
+    let mut take_report = |reporter: &mut IntervalReporter| {
+        if let Some(result) = reporter.report() {
+            on_interval(&result);
+            intervals.push(result);
+        }
+    };
+    while !done.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        if now >= next {
+            take_report(&mut reporter);
+            next += interval;
+        } else {
+            std::thread::sleep(std::cmp::min(next - now, POLL_INTERVAL));
+        }
     }
+    take_report(&mut reporter);
+    intervals
 }
@@ -0,0 +1,567 @@
+use crate::proto::{TestLength, WorkType};
+use crate::report::{LatencyStats, StreamCounters, StreamReport, StreamResult};
+use crate::transport::Transport;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Bytes a pacer may send at once after idling, in seconds of its rate.
+const PACING_BURST: f64 = 0.001;
+/// Every request of a connection rate test starts with the big-endian index
+/// of the client stream that sent it, as the server cannot tell otherwise.
+pub const STREAM_INDEX_SIZE: usize = 8;
+
+/// Token bucket holding a sender to a fixed bitrate.
+pub struct Pacer {
+    bytes_per_sec: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Pacer {
+    /// Paces to `bits_per_second`, letting at least one `bucket_size` through
+    /// at once.
+    pub fn new(bits_per_second: u64, bucket_size: usize) -> Self {
+        let bytes_per_sec = bits_per_second as f64 / 8.;
+        let burst = (bytes_per_sec * PACING_BURST).max(bucket_size as f64);
+        Pacer {
+            bytes_per_sec,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.burst);
+        self.last = now;
+    }
+
+    /// Blocks until `nbytes` may be sent and takes them from the bucket.
+    pub fn wait(&mut self, nbytes: usize) {
+        self.refill();
+        let missing = nbytes as f64 - self.tokens;
+        if missing > 0. {
+            std::thread::sleep(Duration::from_secs_f64(missing / self.bytes_per_sec));
+            self.refill();
+        }
+        // Sleeping overshoots; the debt left is paid back by later buckets.
+        self.tokens -= nbytes as f64;
+    }
+}
+
+/// Sends buckets over `transport` for `length` at no more than `bitrate`
+/// bits per second, followed by the end-of-test marker, updating `counters`
+/// and calling `on_bucket` after every bucket.
+pub fn send_buckets<T, F>(
+    transport: &mut T,
+    bucket: &[u8],
+    length: TestLength,
+    bitrate: Option<u64>,
+    counters: &StreamCounters,
+    mut on_bucket: F,
+) -> io::Result<StreamResult>
+where
+    T: Transport + ?Sized,
+    F: FnMut(),
+{
+    let mut pacer = bitrate.map(|bitrate| Pacer::new(bitrate, bucket.len()));
+    let now = Instant::now();
+    let mut nbytes = 0;
+    let mut nbuckets = 0;
+    loop {
+        let done = match length {
+            TestLength::Repeat(repeat) => nbuckets >= repeat,
+            TestLength::Time(duration) => now.elapsed() >= duration,
+        };
+        if done {
+            break;
+        }
+
+        if let Some(pacer) = pacer.as_mut() {
+            pacer.wait(bucket.len());
+        }
+        transport.send_bucket(bucket)?;
+        nbuckets += 1;
+        nbytes += bucket.len();
+        counters.add_sent(bucket.len());
+        counters.set_retransmits(transport.retransmits());
+        on_bucket();
+    }
+    transport.finish()?;
+
+    Ok(StreamResult {
+        nbytes,
+        elapsed: now.elapsed(),
+    })
+}
+
+/// Receives buckets from `transport` until the peer ends the test, updating
+/// `counters` and calling `on_bucket` after every bucket.
+pub fn recv_buckets<T, F>(
+    transport: &mut T,
+    bucket: &mut [u8],
+    counters: &StreamCounters,
+    mut on_bucket: F,
+) -> io::Result<StreamResult>
+where
+    T: Transport + ?Sized,
+    F: FnMut(),
+{
+    let now = Instant::now();
+    let mut nbytes = 0;
+    // Taken at the last bucket: a datagram transport that lost the end
+    // marker only gives up waiting for it after a timeout.
+    let mut elapsed = None;
+    while let Some(n) = transport.recv_bucket(bucket)? {
+        nbytes += n;
+        elapsed = Some(now.elapsed());
+        counters.add_received(n);
+        on_bucket();
+    }
+
+    Ok(StreamResult {
+        nbytes,
+        elapsed: elapsed.unwrap_or_else(|| now.elapsed()),
+    })
+}
+
+/// Sends `request` and waits for the response to it, one transaction after
+/// another for `length` with requests paced to `bitrate` bits per second,
+/// followed by the end-of-test marker. Updates `counters` and calls
+/// `on_transaction` after every response. Datagram transports do not
+/// retransmit, so there a lost request or response fails the test.
+pub fn request_responses<T, F>(
+    transport: &mut T,
+    request: &[u8],
+    response: &mut [u8],
+    length: TestLength,
+    bitrate: Option<u64>,
+    counters: &StreamCounters,
+    mut on_transaction: F,
+) -> io::Result<StreamReport>
+where
+    T: Transport + ?Sized,
+    F: FnMut(),
+{
+    let mut pacer = bitrate.map(|bitrate| Pacer::new(bitrate, request.len()));
+    let mut latencies = Vec::new();
+    let now = Instant::now();
+    loop {
+        let done = match length {
+            TestLength::Repeat(repeat) => latencies.len() >= repeat,
+            TestLength::Time(duration) => now.elapsed() >= duration,
+        };
+        if done {
+            break;
+        }
+
+        if let Some(pacer) = pacer.as_mut() {
+            pacer.wait(request.len());
+        }
+        let start = Instant::now();
+        transport.send_bucket(request)?;
+        counters.add_sent(request.len());
+        match transport.recv_bucket(response)? {
+            Some(nbytes) if nbytes == response.len() => {}
+            Some(nbytes) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("response of {} bytes, expected {}", nbytes, response.len()),
+                ))
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("no response to request {}", latencies.len()),
+                ))
+            }
+        }
+        latencies.push(start.elapsed());
+        counters.add_received(response.len());
+        counters.set_retransmits(transport.retransmits());
+        on_transaction();
+    }
+    transport.finish()?;
+
+    let elapsed = now.elapsed();
+    Ok(StreamReport {
+        sent: Some(StreamResult {
+            nbytes: latencies.len() * request.len(),
+            elapsed,
+        }),
+        received: Some(StreamResult {
+            nbytes: latencies.len() * response.len(),
+            elapsed,
+        }),
+        latency: LatencyStats::from_samples(&mut latencies, elapsed),
+        ..StreamReport::default()
+    })
+}
+
+/// Answers every request received from `transport` with `response` until
+/// the peer ends the test, updating `counters`.
+pub fn respond_requests<T>(
+    transport: &mut T,
+    request: &mut [u8],
+    response: &[u8],
+    counters: &StreamCounters,
+) -> io::Result<StreamReport>
+where
+    T: Transport + ?Sized,
+{
+    let now = Instant::now();
+    let mut received = 0;
+    let mut sent = 0;
+    while let Some(n) = transport.recv_bucket(request)? {
+        received += n;
+        counters.add_received(n);
+        transport.send_bucket(response)?;
+        sent += response.len();
+        counters.add_sent(response.len());
+    }
+
+    let elapsed = now.elapsed();
+    Ok(StreamReport {
+        sent: Some(StreamResult {
+            nbytes: sent,
+            elapsed,
+        }),
+        received: Some(StreamResult {
+            nbytes: received,
+            elapsed,
+        }),
+        ..StreamReport::default()
+    })
+}
+
+/// Prefixes `request` with the index of the stream sending it, as a
+/// connection rate test needs.
+pub fn indexed_request(stream: usize, request: &[u8]) -> Vec<u8> {
+    let mut message = (stream as u64).to_be_bytes().to_vec();
+    message.extend_from_slice(request);
+    message
+}
+
+/// Like `request_responses`, but sends every request over a new transport
+/// from `connect` and waits for the peer to end it after the response. The
+/// last transport only carries the end-of-test marker. `request` comes from
+/// `indexed_request`; its index is not counted as data.
+pub fn connect_requests<C, T, F>(
+    mut connect: C,
+    request: &[u8],
+    response: &mut [u8],
+    length: TestLength,
+    bitrate: Option<u64>,
+    counters: &StreamCounters,
+    mut on_connection: F,
+) -> io::Result<StreamReport>
+where
+    C: FnMut() -> io::Result<T>,
+    T: Transport,
+    F: FnMut(),
+{
+    let (_, payload) = request.split_at(STREAM_INDEX_SIZE);
+    let mut pacer = bitrate.map(|bitrate| Pacer::new(bitrate, payload.len()));
+    let mut latencies = Vec::new();
+    let mut connect_latencies = Vec::new();
+    let now = Instant::now();
+    loop {
+        let done = match length {
+            TestLength::Repeat(repeat) => latencies.len() >= repeat,
+            TestLength::Time(duration) => now.elapsed() >= duration,
+        };
+        if done {
+            break;
+        }
+
+        if let Some(pacer) = pacer.as_mut() {
+            pacer.wait(payload.len());
+        }
+        let start = Instant::now();
+        let mut transport = connect()?;
+        connect_latencies.push(start.elapsed());
+        transport.send_bucket(request)?;
+        counters.add_sent(payload.len());
+        match transport.recv_bucket(response)? {
+            Some(nbytes) if nbytes == response.len() => {}
+            Some(nbytes) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("response of {} bytes, expected {}", nbytes, response.len()),
+                ))
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("no response on connection {}", latencies.len()),
+                ))
+            }
+        }
+        // The peer closes first, so that connections linger in TIME_WAIT on
+        // its end rather than using up the local ports of this one.
+        if transport.recv_bucket(response)?.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("more than one response on connection {}", latencies.len()),
+            ));
+        }
+        transport.close()?;
+        latencies.push(start.elapsed());
+        counters.add_received(response.len());
+        on_connection();
+    }
+    let mut transport = connect()?;
+    transport.finish()?;
+    transport.close()?;
+
+    let elapsed = now.elapsed();
+    Ok(StreamReport {
+        sent: Some(StreamResult {
+            nbytes: latencies.len() * payload.len(),
+            elapsed,
+        }),
+        received: Some(StreamResult {
+            nbytes: latencies.len() * response.len(),
+            elapsed,
+        }),
+        latency: LatencyStats::from_samples(&mut latencies, elapsed),
+        connect: LatencyStats::from_samples(&mut connect_latencies, elapsed),
+        ..StreamReport::default()
+    })
+}
+
+/// Server side results of the streams of a connection rate test, which any
+/// of its handlers may get the connections of.
+pub struct ConnectionStreams {
+    start: Instant,
+    streams: Vec<Mutex<StreamReport>>,
+}
+
+impl ConnectionStreams {
+    pub fn new(nstreams: usize) -> Self {
+        let report = StreamReport {
+            sent: Some(StreamResult::default()),
+            received: Some(StreamResult::default()),
+            ..StreamReport::default()
+        };
+        ConnectionStreams {
+            start: Instant::now(),
+            streams: (0..nstreams).map(|_| Mutex::new(report)).collect(),
+        }
+    }
+
+    /// Adds a transaction to `stream`, whose results then run until now.
+    fn add(&self, stream: usize, received: usize, sent: usize) -> io::Result<()> {
+        let report = self.streams.get(stream).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("request of unknown stream {}", stream),
+            )
+        })?;
+        let mut report = report.lock().unwrap();
+        let elapsed = self.start.elapsed();
+        if let Some(result) = report.received.as_mut() {
+            result.nbytes += received;
+            result.elapsed = elapsed;
+        }
+        if let Some(result) = report.sent.as_mut() {
+            result.nbytes += sent;
+            result.elapsed = elapsed;
+        }
+        Ok(())
+    }
+
+    pub fn reports(&self) -> Vec<StreamReport> {
+        self.streams
+            .iter()
+            .map(|report| *report.lock().unwrap())
+            .collect()
+    }
+}
+
+/// Answers the request of every transport from `accept` with `response` and
+/// ends the transport, until one carries the end-of-test marker instead.
+/// Each transaction counts towards the stream its request names.
+pub fn respond_connections<A, T>(
+    mut accept: A,
+    request: &mut [u8],
+    response: &[u8],
+    streams: &ConnectionStreams,
+) -> io::Result<()>
+where
+    A: FnMut() -> io::Result<T>,
+    T: Transport,
+{
+    loop {
+        let mut transport = accept()?;
+        let nbytes = match transport.recv_bucket(request)? {
+            Some(nbytes) if nbytes > STREAM_INDEX_SIZE => nbytes,
+            Some(nbytes) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("request of {} bytes has no stream index", nbytes),
+                ))
+            }
+            None => {
+                transport.close()?;
+                return Ok(());
+            }
+        };
+        transport.send_bucket(response)?;
+        transport.finish()?;
+        transport.close()?;
+
+        let mut stream = [0; STREAM_INDEX_SIZE];
+        stream.copy_from_slice(&request[..STREAM_INDEX_SIZE]);
+        streams.add(
+            u64::from_be_bytes(stream) as usize,
+            nbytes - STREAM_INDEX_SIZE,
+            response.len(),
+        )?;
+    }
+}
+
+/// Runs one stream of a test, doing `work_type` from this end's point of view,
+/// and closes the transport afterwards. `counters` follow both directions while
+/// `on_bucket` is called for every bucket sent, or received when this end only
+/// receives, or for every transaction of a request/response test. `bucket`
+/// holds the requests of such a test.
+pub fn run_stream<F>(
+    mut transport: Box<dyn Transport>,
+    work_type: WorkType,
+    bucket: &mut [u8],
+    length: TestLength,
+    bitrate: Option<u64>,
+    counters: Arc<StreamCounters>,
+    on_bucket: F,
+) -> io::Result<StreamReport>
+where
+    F: FnMut(),
+{
+    counters.set_kcp_sampler(transport.kcp_sampler());
+    let mut report = match work_type {
+        WorkType::Send => StreamReport {
+            sent: Some(send_buckets(
+                &mut *transport,
+                bucket,
+                length,
+                bitrate,
+                &counters,
+                on_bucket,
+            )?),
+            ..StreamReport::default()
+        },
+        WorkType::Recv => StreamReport {
+            received: Some(recv_buckets(&mut *transport, bucket, &counters, on_bucket)?),
+            ..StreamReport::default()
+        },
+        WorkType::Bidir => {
+            return run_bidir_stream(transport, bucket, length, bitrate, counters, on_bucket)
+        }
+        WorkType::Request { response_size } => request_responses(
+            &mut *transport,
+            bucket,
+            &mut vec![0; response_size][..],
+            length,
+            bitrate,
+            &counters,
+            on_bucket,
+        )?,
+        WorkType::Respond { response_size } => respond_requests(
+            &mut *transport,
+            bucket,
+            &vec![0; response_size][..],
+            &counters,
+        )?,
+        WorkType::Connect { .. } | WorkType::Accept { .. } => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "connection rate tests open their own transports",
+            ))
+        }
+    };
+    let stats = transport.stats();
+    report.wire = stats.wire;
+    report.udp = stats.udp;
+    report.kcp = transport.kcp_sample();
+    transport.close()?;
+
+    Ok(report)
+}
+
+fn run_bidir_stream<F>(
+    transport: Box<dyn Transport>,
+    bucket: &mut [u8],
+    length: TestLength,
+    bitrate: Option<u64>,
+    counters: Arc<StreamCounters>,
+    on_bucket: F,
+) -> io::Result<StreamReport>
+where
+    F: FnMut(),
+{
+    let (mut send_half, mut recv_half) = transport.split()?;
+    let mut recv_bucket: Vec<u8> = vec![0; bucket.len()];
+    let recv_counters = counters.clone();
+    let receiver = std::thread::spawn(move || {
+        let result = recv_buckets(&mut *recv_half, &mut recv_bucket[..], &recv_counters, || {});
+        (result, recv_half)
+    });
+
+    let sent = send_buckets(
+        &mut *send_half,
+        bucket,
+        length,
+        bitrate,
+        &counters,
+        on_bucket,
+    );
+    if sent.is_err() {
+        // Stops the receiver early where the transport can, it otherwise
+        // runs until the peer ends its own direction.
+        let _ = send_half.close();
+    }
+    let (received, mut recv_half) = receiver
+        .join()
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "stream receiver panicked"))?;
+    let sent = sent?;
+    let received = received?;
+    // Both halves share the wire counters and state of the stream.
+    let wire = send_half.stats().wire;
+    let kcp = send_half.kcp_sample();
+    let udp = recv_half.stats().udp;
+    send_half.close()?;
+    recv_half.close()?;
+
+    Ok(StreamReport {
+        sent: Some(sent),
+        received: Some(received),
+        wire,
+        kcp,
+        udp,
+        latency: None,
+        connect: None,
+    })
+}
+
+/// Waits for every worker, even after one failed, and returns their results
+/// in order or the first error.
+pub fn join_all<T>(workers: Vec<JoinHandle<io::Result<T>>>) -> io::Result<Vec<T>> {
+    let results = workers
+        .into_iter()
+        .map(|worker| {
+            worker.join().unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "stream worker panicked",
+                ))
+            })
+        })
+        .collect::<Vec<_>>();
+    results.into_iter().collect()
+}
@@ -0,0 +1,9 @@
+pub mod client;
+pub mod engine;
+pub mod loopback;
+pub mod proto;
+pub mod report;
+pub mod server;
+pub mod sweep;
+pub mod transport;
+pub mod utils;
@@ -0,0 +1,204 @@
+//! Client and server in one process, for benchmarking the user-space stack
+//! and for tests on machines without TAP privileges. Their smoltcp stacks are
+//! linked by an in-memory pipe; the kernel transports use the loopback
+//! interface.
+
+use crate::client::{self, ClientConfig};
+use crate::report::TestReport;
+use crate::server::{self, ServerConfig};
+use crate::transport::impair::ImpairmentConfig;
+use crate::transport::pipe::{self, PIPE_MTU};
+use crate::transport::stack::{Stack, StackConfig};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+
+const CLIENT_ETHERNET_ADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x01]);
+const SERVER_ETHERNET_ADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x02]);
+
+fn client_cidr() -> IpCidr {
+    IpCidr::new(IpAddress::v4(10, 69, 0, 1), 24)
+}
+
+fn server_cidr() -> IpCidr {
+    IpCidr::new(IpAddress::v4(10, 69, 0, 2), 24)
+}
+
+/// Runs the test of `config` against a server in this process and returns
+/// its report. `config.address` and `config.stack` are replaced.
+///
+/// Of `link`, only the impairments, applied to both stacks, and the pcap
+/// file, which records the frames of the client stack, are used: the pipe
+/// carries every frame of the test, so one capture sees both sides.
+pub fn run(config: &ClientConfig, link: &StackConfig) -> io::Result<TestReport> {
+    let (client_end, server_end) = pipe::pair(PIPE_MTU);
+    let impairment = &link.impairment;
+    let client_stack = Stack::start_pipe(
+        client_end,
+        client_cidr(),
+        CLIENT_ETHERNET_ADDR,
+        impairment,
+        link.pcap.as_deref(),
+    )?;
+    // Its own seed, so that both directions do not lose the same frames.
+    let server_impairment = ImpairmentConfig {
+        seed: impairment.seed.wrapping_mul(2654435761).max(1),
+        ..impairment.clone()
+    };
+    let server_stack = Stack::start_pipe(
+        server_end,
+        server_cidr(),
+        SERVER_ETHERNET_ADDR,
+        &server_impairment,
+        None,
+    )?;
+
+    let server_config = ServerConfig {
+        address: [127, 0, 0, 1].into(),
+        stack: Some(server_stack),
+        quiet: config.quiet,
+        ..ServerConfig::default()
+    };
+    let listener = TcpListener::bind(SocketAddr::new(server_config.address, 0))?;
+    let address = listener.local_addr()?;
+    let server = std::thread::spawn(move || server::serve_one(&server_config, &listener));
+
+    let report = client::run(&ClientConfig {
+        address,
+        stack: Some(client_stack),
+        ..config.clone()
+    })?;
+    // Only waited for on success: after a failure the server may still be
+    // blocked on streams that will never connect.
+    server.join().unwrap()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{TestLength, WorkType};
+    use crate::transport::TransportKind;
+
+    fn loopback_config(transport: TransportKind, work_type: WorkType) -> ClientConfig {
+        ClientConfig {
+            transport,
+            work_type,
+            bucket_size: 1024,
+            length: TestLength::Repeat(100),
+            quiet: true,
+            ..ClientConfig::default()
+        }
+    }
+
+    #[test]
+    fn kcp_streams_over_loopback() {
+        let mut config = loopback_config(TransportKind::Kcp, WorkType::Bidir);
+        config.nstreams = 3;
+        let report = run(&config, &StackConfig::default()).unwrap();
+        for index in 0..3 {
+            for &(sent, received) in &[report.upload(index), report.download(index)] {
+                assert_eq!(sent.unwrap().nbytes, 100 * 1024);
+                assert_eq!(received.unwrap().nbytes, 100 * 1024);
+            }
+        }
+    }
+
+    #[test]
+    fn smoltcp_udp_over_pipe() {
+        let config = loopback_config(TransportKind::SmoltcpUdp, WorkType::Send);
+        let report = run(&config, &StackConfig::default()).unwrap();
+        let (sent, received) = report.upload(0);
+        assert_eq!(sent.unwrap().nbytes, 100 * 1024);
+        assert_eq!(received.unwrap().nbytes, 100 * 1024);
+    }
+
+    #[test]
+    fn smoltcp_udp_over_pipe_reversed() {
+        let config = loopback_config(TransportKind::SmoltcpUdp, WorkType::Recv);
+        let report = run(&config, &StackConfig::default()).unwrap();
+        let (sent, received) = report.download(0);
+        assert_eq!(sent.unwrap().nbytes, 100 * 1024);
+        assert_eq!(received.unwrap().nbytes, 100 * 1024);
+    }
+
+    #[test]
+    fn smoltcp_tcp_over_pipe_both_ways() {
+        let config = loopback_config(TransportKind::SmoltcpTcp, WorkType::Bidir);
+        let report = run(&config, &StackConfig::default()).unwrap();
+        for &(sent, received) in &[report.upload(0), report.download(0)] {
+            assert_eq!(sent.unwrap().nbytes, 100 * 1024);
+            assert_eq!(received.unwrap().nbytes, 100 * 1024);
+        }
+    }
+
+    #[test]
+    fn smoltcp_tcp_over_lossy_pipe() {
+        let config = loopback_config(TransportKind::SmoltcpTcp, WorkType::Send);
+        let link = StackConfig {
+            impairment: ImpairmentConfig {
+                drop_pct: 5,
+                delay_ms: 1,
+                reorder_pct: 10,
+                reorder_delay_ms: 2,
+                ..ImpairmentConfig::default()
+            },
+            ..StackConfig::default()
+        };
+        let report = run(&config, &link).unwrap();
+        let (sent, received) = report.upload(0);
+        assert_eq!(sent.unwrap().nbytes, 100 * 1024);
+        assert_eq!(received.unwrap().nbytes, 100 * 1024);
+    }
+
+    #[test]
+    fn smoltcp_tcp_request_response() {
+        let mut config = loopback_config(
+            TransportKind::SmoltcpTcp,
+            WorkType::Request { response_size: 200 },
+        );
+        config.bucket_size = 10;
+        let report = run(&config, &StackConfig::default()).unwrap();
+        let latency = report.client[0].latency.unwrap();
+        assert_eq!(latency.count, 100);
+        assert!(latency.p50 <= latency.p99 && latency.p99 <= latency.max);
+        let (_, received) = report.download(0);
+        assert_eq!(received.unwrap().nbytes, 100 * 200);
+    }
+
+    #[test]
+    fn smoltcp_udp_request_response() {
+        let mut config = loopback_config(
+            TransportKind::SmoltcpUdp,
+            WorkType::Request { response_size: 200 },
+        );
+        // As with --rr, requests smaller than the stream hello and responses.
+        config.bucket_size = 1;
+        let report = run(&config, &StackConfig::default()).unwrap();
+        assert_eq!(report.client[0].latency.unwrap().count, 100);
+        let (_, received) = report.download(0);
+        assert_eq!(received.unwrap().nbytes, 100 * 200);
+    }
+
+    #[test]
+    fn smoltcp_tcp_connection_rate() {
+        let mut config = loopback_config(
+            TransportKind::SmoltcpTcp,
+            WorkType::Connect { response_size: 20 },
+        );
+        config.nstreams = 2;
+        let report = run(&config, &StackConfig::default()).unwrap();
+        for index in 0..2 {
+            let connect = report.client[index].connect.unwrap();
+            assert_eq!(connect.count, 100);
+            assert_eq!(report.client[index].latency.unwrap().count, 100);
+            // Any server handler may get a connection, yet every stream's
+            // results add up.
+            let (sent, received) = report.upload(index);
+            assert_eq!(sent.unwrap().nbytes, received.unwrap().nbytes);
+            let (sent, received) = report.download(index);
+            assert_eq!(sent.unwrap().nbytes, 100 * 20);
+            assert_eq!(received.unwrap().nbytes, 100 * 20);
+        }
+    }
+}
@@ -1,5 +1,498 @@
-pub mod proto;
+use argparse::{ArgumentParser, List, Store, StoreFalse, StoreOption, StoreTrue};
+use rust_iperf::client::{self, ClientConfig};
+use rust_iperf::loopback;
+use rust_iperf::proto::{TestLength, WorkType, DEFAULT_PORT};
+use rust_iperf::report;
+use rust_iperf::server::{self, ServerConfig};
+use rust_iperf::sweep::{self, SweepConfig};
+use rust_iperf::transport::stack::{Stack, StackConfig};
+use rust_iperf::transport::TransportKind;
+use rust_iperf::utils;
+use std::io::{stderr, stdout};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy)]
+enum Command {
+    Client,
+    Server,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(Command::Client),
+            "server" => Ok(Command::Server),
+            _ => Err(format!(
+                "unknown command {:?}, expected client or server",
+                s
+            )),
+        }
+    }
+}
+
+fn parse_args_or_exit(ap: &ArgumentParser, args: Vec<String>) {
+    if let Err(code) = ap.parse(args, &mut stdout(), &mut stderr()) {
+        std::process::exit(code);
+    }
+}
+
+/// Adds the options describing the device of the smoltcp stack.
+fn refer_stack_options<'a>(ap: &mut ArgumentParser<'a>, config: &'a mut StackConfig) {
+    ap.refer(&mut config.interface).add_option(
+        &["--smoltcp_interface"],
+        Store,
+        "TAP device or interface the smoltcp stack runs on",
+    );
+    ap.refer(&mut config.device).add_option(
+        &["--smoltcp_device"],
+        Store,
+        "smoltcp device: tap or raw",
+    );
+    ap.refer(&mut config.ip_cidr).add_option(
+        &["--smoltcp_ip"],
+        StoreOption,
+        "Address of the smoltcp stack such as 192.168.69.2/24, required on tap devices, defaults to the interface's on raw ones",
+    );
+    ap.refer(&mut config.gateway).add_option(
+        &["--smoltcp_gateway"],
+        StoreOption,
+        "Default gateway of the smoltcp stack, defaults to the interface's",
+    );
+    ap.refer(&mut config.ethernet_addr).add_option(
+        &["--smoltcp_mac"],
+        StoreOption,
+        "MAC address of the smoltcp stack",
+    );
+    ap.refer(&mut config.pcap).add_option(
+        &["--smoltcp_pcap"],
+        StoreOption,
+        "Write the frames of the smoltcp stack to this pcap file",
+    );
+    let impairment = &mut config.impairment;
+    ap.refer(&mut impairment.drop_pct).add_option(
+        &["--impair_drop"],
+        Store,
+        "Percentage of frames the smoltcp stack drops",
+    );
+    ap.refer(&mut impairment.corrupt_pct).add_option(
+        &["--impair_corrupt"],
+        Store,
+        "Percentage of frames the smoltcp stack corrupts",
+    );
+    ap.refer(&mut impairment.max_rate).add_option(
+        &["--impair_rate"],
+        Store,
+        "Frames per second the smoltcp stack lets through each way, 0 for no limit",
+    );
+    ap.refer(&mut impairment.max_packet_size).add_option(
+        &["--impair_max_packet"],
+        Store,
+        "Size above which the smoltcp stack drops frames, 0 for no limit",
+    );
+    ap.refer(&mut impairment.delay_ms).add_option(
+        &["--impair_delay"],
+        Store,
+        "Milliseconds the smoltcp stack delays received frames by",
+    );
+    ap.refer(&mut impairment.reorder_pct).add_option(
+        &["--impair_reorder"],
+        Store,
+        "Percentage of received frames delayed further to reorder them",
+    );
+    ap.refer(&mut impairment.reorder_delay_ms).add_option(
+        &["--impair_reorder_delay"],
+        Store,
+        "Milliseconds reordered frames are delayed by on top of --impair_delay",
+    );
+    ap.refer(&mut impairment.seed).add_option(
+        &["--impair_seed"],
+        Store,
+        "Seed of the random impairments",
+    );
+}
+
+/// Starts the smoltcp stack if a device was given.
+fn start_stack(config: &StackConfig, command: &str) -> Option<Arc<Stack>> {
+    if config.interface.is_empty() {
+        return None;
+    }
+    match Stack::start(config) {
+        Ok(stack) => Some(stack),
+        Err(err) => {
+            eprintln!("rust-iperf {}: smoltcp: {}", command, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Replaces `values` with the comma separated `list` unless it is empty.
+fn parse_sweep_list<T: FromStr>(list: &str, values: &mut Vec<T>) {
+    if list.is_empty() {
+        return;
+    }
+    match sweep::parse_list(list) {
+        Ok(list) => *values = list,
+        Err(err) => {
+            eprintln!("rust-iperf client: {}", err);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn client_main(args: Vec<String>) {
+    let mut config = ClientConfig::default();
+    let mut stack_config = StackConfig::default();
+    let mut bucket_size: usize = 0;
+    let mut repeat: usize = 10000;
+    let mut time: f64 = 0.0;
+    let mut interval: f64 = 0.0;
+    let mut address = String::new();
+    let mut bitrate = String::new();
+    let mut json = false;
+    let mut loopback = false;
+    let mut reverse = false;
+    let mut bidir = false;
+    let mut rr = false;
+    let mut crr = false;
+    let mut response_size: usize = 1;
+    let mut kcp_sweep = false;
+    let mut sweep_nodelay = String::new();
+    let mut sweep_interval = String::new();
+    let mut sweep_resend = String::new();
+    let mut sweep_wnd = String::new();
+    let mut sweep_mtu = String::new();
+    {
+        // this block limits scope of borrows by ap.refer() method
+        let mut ap = ArgumentParser::new();
+        ap.set_description("rust-iperf client.");
+        ap.refer(&mut config.transport).add_option(
+            &["--transport"],
+            Store,
+            "Transport: tcp, kcp, smoltcp-udp, udp or smoltcp-tcp",
+        );
+        ap.refer(&mut address).add_option(
+            &["--address"],
+            Store,
+            "Server control address: host[:port], on the default port without one",
+        );
+        ap.refer(&mut bucket_size).add_option(
+            &["--bucket_size"],
+            Store,
+            "Bucket size, defaults to a size suited to the transport",
+        );
+        ap.refer(&mut repeat).add_option(
+            &["--repeat"],
+            Store,
+            "Number of buckets, or requests, per stream",
+        );
+        ap.refer(&mut time).add_option(
+            &["--time"],
+            Store,
+            "Seconds to send for, overrides --repeat when positive",
+        );
+        ap.refer(&mut interval).add_option(
+            &["--interval"],
+            Store,
+            "Seconds between throughput reports, replaces the progress bars",
+        );
+        ap.refer(&mut loopback).add_option(
+            &["--loopback"],
+            StoreTrue,
+            "Run the server in this process, smoltcp over an in-memory link",
+        );
+        ap.refer(&mut bitrate).add_option(
+            &["--bitrate"],
+            Store,
+            "Payload bits per second of each sender, e.g. 2G or 500M",
+        );
+        ap.refer(&mut config.kernel_pacing).add_option(
+            &["--kernel_pacing"],
+            StoreTrue,
+            "Also cap tcp streams with SO_MAX_PACING_RATE",
+        );
+        ap.refer(&mut config.smoltcp_tcp.rx_buffer_size).add_option(
+            &["--smoltcp_tcp_rx_buffer"],
+            Store,
+            "Receive buffer of smoltcp tcp connections in bytes",
+        );
+        ap.refer(&mut config.smoltcp_tcp.tx_buffer_size).add_option(
+            &["--smoltcp_tcp_tx_buffer"],
+            Store,
+            "Send buffer of smoltcp tcp connections in bytes",
+        );
+        ap.refer(&mut config.smoltcp_tcp.nagle).add_option(
+            &["--smoltcp_tcp_no_nagle"],
+            StoreFalse,
+            "Disable Nagle's algorithm on smoltcp tcp connections",
+        );
+        ap.refer(&mut config.smoltcp_tcp.ack_delay).add_option(
+            &["--smoltcp_tcp_ack_delay"],
+            Store,
+            "ACK delay of smoltcp tcp connections in milliseconds, 0 disables it",
+        );
+        ap.refer(&mut config.kcp.mode).add_option(
+            &["--kcp_mode"],
+            Store,
+            "KCP mode: stream or message",
+        );
+        ap.refer(&mut config.kcp.snd_wnd).add_option(
+            &["--kcp_sndwnd"],
+            Store,
+            "KCP send window in segments",
+        );
+        ap.refer(&mut config.kcp.rcv_wnd).add_option(
+            &["--kcp_rcvwnd"],
+            Store,
+            "KCP receive window in segments",
+        );
+        ap.refer(&mut config.kcp.nodelay).add_option(
+            &["--kcp_nodelay"],
+            Store,
+            "KCP nodelay: true or false",
+        );
+        ap.refer(&mut config.kcp.interval).add_option(
+            &["--kcp_interval"],
+            Store,
+            "KCP update interval in milliseconds",
+        );
+        ap.refer(&mut config.kcp.resend).add_option(
+            &["--kcp_resend"],
+            Store,
+            "KCP fast resend threshold, 0 disables fast resend",
+        );
+        ap.refer(&mut config.kcp.no_congestion).add_option(
+            &["--kcp_nc"],
+            Store,
+            "Disable KCP congestion control: true or false",
+        );
+        ap.refer(&mut config.kcp.fast_resend).add_option(
+            &["--kcp_fast_resend"],
+            StoreOption,
+            "KCP fast resend threshold, overrides --kcp_resend",
+        );
+        ap.refer(&mut config.kcp.rx_minrto).add_option(
+            &["--kcp_minrto"],
+            Store,
+            "KCP minimum retransmission timeout in milliseconds",
+        );
+        ap.refer(&mut config.kcp.mtu)
+            .add_option(&["--kcp_mtu"], Store, "KCP MTU in bytes");
+        ap.refer(&mut kcp_sweep).add_option(
+            &["--kcp_sweep"],
+            StoreTrue,
+            "Run one KCP test per combination of the --sweep_* values and rank them",
+        );
+        ap.refer(&mut sweep_nodelay).add_option(
+            &["--sweep_nodelay"],
+            Store,
+            "Comma separated KCP nodelay values to sweep",
+        );
+        ap.refer(&mut sweep_interval).add_option(
+            &["--sweep_interval"],
+            Store,
+            "Comma separated KCP update intervals to sweep",
+        );
+        ap.refer(&mut sweep_resend).add_option(
+            &["--sweep_resend"],
+            Store,
+            "Comma separated KCP resend thresholds to sweep",
+        );
+        ap.refer(&mut sweep_wnd).add_option(
+            &["--sweep_wnd"],
+            Store,
+            "Comma separated KCP window sizes to sweep",
+        );
+        ap.refer(&mut sweep_mtu).add_option(
+            &["--sweep_mtu"],
+            Store,
+            "Comma separated KCP MTUs to sweep",
+        );
+        ap.refer(&mut json).add_option(
+            &["--json"],
+            StoreTrue,
+            "Print the results as a single JSON document",
+        );
+        ap.refer(&mut config.nstreams).add_option(
+            &["--nstreams"],
+            Store,
+            "Number of parallel streams",
+        );
+        ap.refer(&mut reverse).add_option(
+            &["-R", "--reverse"],
+            StoreTrue,
+            "Let the server send and the client receive",
+        );
+        ap.refer(&mut bidir).add_option(
+            &["--bidir"],
+            StoreTrue,
+            "Send and receive on every stream at the same time",
+        );
+        ap.refer(&mut rr).add_option(
+            &["--rr"],
+            StoreTrue,
+            "Request/response latency test: send requests of --bucket_size bytes one at a time",
+        );
+        ap.refer(&mut crr).add_option(
+            &["--crr"],
+            StoreTrue,
+            "Connection rate test: like --rr but over a new connection per request",
+        );
+        ap.refer(&mut response_size).add_option(
+            &["--response_size"],
+            Store,
+            "Size of the responses of --rr and --crr in bytes",
+        );
+        refer_stack_options(&mut ap, &mut stack_config);
+        parse_args_or_exit(&ap, args);
+    }
+    if !address.is_empty() {
+        match utils::resolve_address(&address, DEFAULT_PORT) {
+            Ok(address) => config.address = address,
+            Err(err) => {
+                eprintln!("rust-iperf client: {}", err);
+                std::process::exit(2);
+            }
+        }
+    }
+    config.work_type = match (reverse, bidir, rr, crr) {
+        (false, false, false, false) => WorkType::Send,
+        (true, false, false, false) => WorkType::Recv,
+        (false, true, false, false) => WorkType::Bidir,
+        (false, false, true, false) => WorkType::Request { response_size },
+        (false, false, false, true) => WorkType::Connect { response_size },
+        _ => {
+            eprintln!("rust-iperf client: --reverse, --bidir, --rr and --crr exclude each other");
+            std::process::exit(2);
+        }
+    };
+    config.stack = start_stack(&stack_config, "client");
+    // Before the bucket size defaults, which depend on the transport.
+    if kcp_sweep {
+        config.transport = TransportKind::Kcp;
+    }
+    config.bucket_size = match bucket_size {
+        0 if rr || crr => 1,
+        0 => config.transport.default_bucket_size(),
+        bucket_size => bucket_size,
+    };
+    config.length = if time > 0.0 {
+        TestLength::Time(Duration::from_secs_f64(time))
+    } else {
+        TestLength::Repeat(repeat)
+    };
+    if interval > 0.0 {
+        config.interval = Some(Duration::from_secs_f64(interval));
+    }
+    if !bitrate.is_empty() {
+        match utils::parse_bitrate(&bitrate) {
+            Ok(bitrate) => config.bitrate = Some(bitrate),
+            Err(err) => {
+                eprintln!("rust-iperf client: {}", err);
+                std::process::exit(2);
+            }
+        }
+    }
+    config.quiet = json;
+
+    if kcp_sweep {
+        let mut sweep_config = SweepConfig::default();
+        parse_sweep_list(&sweep_nodelay, &mut sweep_config.nodelay);
+        parse_sweep_list(&sweep_interval, &mut sweep_config.interval);
+        parse_sweep_list(&sweep_resend, &mut sweep_config.resend);
+        parse_sweep_list(&sweep_wnd, &mut sweep_config.wnd);
+        parse_sweep_list(&sweep_mtu, &mut sweep_config.mtu);
+        let results = sweep::run(&config, &sweep_config);
+        if json {
+            let json_results = sweep::json_results(&results);
+            println!("{}", serde_json::to_string_pretty(&json_results).unwrap());
+        } else {
+            sweep::print_table(&results);
+        }
+        return;
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let result = if loopback {
+        loopback::run(&config, &stack_config)
+    } else {
+        client::run(&config)
+    };
+    let test_report = match result {
+        Ok(test_report) => test_report,
+        Err(err) => {
+            eprintln!("rust-iperf client: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if json {
+        let json_report = report::json_report(
+            utils::host_info(),
+            config.address,
+            timestamp,
+            config.perf_request(),
+            &test_report,
+        );
+        println!("{}", serde_json::to_string_pretty(&json_report).unwrap());
+    } else {
+        report::print_test_report(&test_report);
+    }
+}
+
+fn server_main(args: Vec<String>) {
+    let mut config = ServerConfig::default();
+    let mut stack_config = StackConfig::default();
+    {
+        // this block limits scope of borrows by ap.refer() method
+        let mut ap = ArgumentParser::new();
+        ap.set_description("rust-iperf server.");
+        ap.refer(&mut config.address)
+            .add_option(&["--address"], Store, "Listening address");
+        ap.refer(&mut config.port)
+            .add_option(&["--port"], Store, "Control port to listen on");
+        ap.refer(&mut config.bucket_size).add_option(
+            &["--bucket_size"],
+            Store,
+            "Largest bucket accepted from a client",
+        );
+        refer_stack_options(&mut ap, &mut stack_config);
+        parse_args_or_exit(&ap, args);
+    }
+    config.stack = start_stack(&stack_config, "server");
+
+    if let Err(err) = server::run(&config) {
+        eprintln!("rust-iperf server: {}", err);
+        std::process::exit(1);
+    }
+}
 
 fn main() {
-    println!("Hello, world!");
+    let mut command = Command::Client;
+    let mut args: Vec<String> = Vec::new();
+    {
+        // this block limits scope of borrows by ap.refer() method
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Network throughput test over TCP, KCP, smoltcp and UDP.");
+        ap.refer(&mut command).required().add_argument(
+            "command",
+            Store,
+            "Command to run: client or server",
+        );
+        ap.refer(&mut args)
+            .add_argument("arguments", List, "Arguments for the command");
+        ap.stop_on_first_argument(true);
+        ap.parse_args_or_exit();
+    }
+
+    args.insert(0, format!("rust-iperf {:?}", command).to_lowercase());
+    match command {
+        Command::Client => client_main(args),
+        Command::Server => server_main(args),
+    }
 }
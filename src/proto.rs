@@ -1,12 +1,347 @@
+use crate::report::{KcpSample, LatencyStats, StreamReport, StreamResult, UdpStats, WireStats};
+use crate::transport::kcp::KcpConfig;
+use crate::transport::smoltcp::SmoltcpTcpConfig;
+use crate::transport::TransportKind;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::io;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Version of the control protocol, bumped on every incompatible change.
+pub const PROTOCOL_VERSION: u32 = 17;
+/// Control port the server listens on unless told otherwise.
+pub const DEFAULT_PORT: u16 = 63590;
+/// Upper bound on the size of a control message.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Direction of the data, seen from the client.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum WorkType {
     Send,
     Recv,
+    /// Both ends send and receive on every stream at the same time.
+    Bidir,
+    /// Request/response test: the client sends requests of `bucket_size`
+    /// bytes one at a time and waits for a response to each.
+    Request {
+        response_size: usize,
+    },
+    /// What the server does in a request/response test.
+    Respond {
+        response_size: usize,
+    },
+    /// Connection rate test: like `Request`, but every request goes over a
+    /// new connection, which the server closes after responding.
+    Connect {
+        response_size: usize,
+    },
+    /// What the server does in a connection rate test.
+    Accept {
+        response_size: usize,
+    },
+}
+
+impl WorkType {
+    /// What the other end of the test does.
+    pub fn reversed(&self) -> WorkType {
+        match *self {
+            WorkType::Send => WorkType::Recv,
+            WorkType::Recv => WorkType::Send,
+            WorkType::Bidir => WorkType::Bidir,
+            WorkType::Request { response_size } => WorkType::Respond { response_size },
+            WorkType::Respond { response_size } => WorkType::Request { response_size },
+            WorkType::Connect { response_size } => WorkType::Accept { response_size },
+            WorkType::Accept { response_size } => WorkType::Connect { response_size },
+        }
+    }
+
+    /// Size of the responses of a request/response or connection rate test.
+    pub fn response_size(&self) -> Option<usize> {
+        match *self {
+            WorkType::Request { response_size }
+            | WorkType::Respond { response_size }
+            | WorkType::Connect { response_size }
+            | WorkType::Accept { response_size } => Some(response_size),
+            WorkType::Send | WorkType::Recv | WorkType::Bidir => None,
+        }
+    }
+}
+
+/// How long the senders of a test keep sending.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TestLength {
+    /// Send this many buckets, or requests, on every stream.
+    Repeat(usize),
+    /// Send buckets until this much time has passed.
+    Time(Duration),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// First message on the control connection, describing the whole test.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PerfRequest {
+    /// Must stay the first field, so that `read_request` finds it in the
+    /// first four bytes whatever the version.
+    pub version: u32,
     pub work_type: WorkType,
+    pub transport: TransportKind,
+    pub bucket_size: usize,
+    pub length: TestLength,
+    pub nstreams: usize,
+    /// Payload bits per second each sender is paced to, unlimited if `None`.
+    pub bitrate: Option<u64>,
+    /// Also cap TCP streams with the kernel's `SO_MAX_PACING_RATE`.
+    pub kernel_pacing: bool,
+    /// Tuning of KCP streams, ignored by the other transports.
+    pub kcp: KcpConfig,
+    /// Tuning of smoltcp TCP streams, ignored by the other transports.
+    pub smoltcp_tcp: SmoltcpTcpConfig,
+}
+
+impl PerfRequest {
+    /// Kernel pacing rate in bytes per second, if kernel pacing is on.
+    pub fn pacing_rate(&self) -> Option<u64> {
+        match self.bitrate {
+            Some(bitrate) if self.kernel_pacing => Some(bitrate / 8),
+            _ => None,
+        }
+    }
+}
+
+/// Server reply to a `PerfRequest`. Its layout must not change, so that
+/// clients of every version can read a rejection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PerfResponse {
+    /// The data streams should connect to `data_addr`, or to its port on the
+    /// control address of the server if its address is unspecified. KCP
+    /// stream `i` uses the conversation id `kcp_conv + i`.
+    Accept {
+        data_addr: SocketAddr,
+        kcp_conv: u32,
+    },
+    Reject {
+        reason: String,
+    },
+}
+
+/// Sent by the server over the control connection once every stream is done.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PerfResults {
+    /// Server side report of every stream, in the order the streams connected.
+    pub streams: Vec<StreamReport>,
+}
+
+/// Document printed by `rust-iperf client --json` once the test is over.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsonReport {
+    pub start: TestStart,
+    /// Empty unless the client reported intervals.
+    pub intervals: Vec<IntervalResult>,
+    pub end: TestEnd,
+}
+
+/// What was tested, from where and when.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TestStart {
+    pub host: HostInfo,
+    /// Control address of the server.
+    pub server: SocketAddr,
+    /// Seconds since the Unix epoch when the test started.
+    pub timestamp: u64,
+    pub request: PerfRequest,
+}
+
+/// The machine the client ran on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HostInfo {
+    pub hostname: String,
+    pub system: String,
+    pub release: String,
+    pub machine: String,
+}
+
+/// Bytes moved during one interval, in seconds since the test started.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IntervalResult {
+    pub start: f64,
+    pub end: f64,
+    pub streams: Vec<IntervalStream>,
+    pub sum: IntervalStream,
+}
+
+/// What one stream, or all of them, moved during an interval.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct IntervalStream {
+    pub sent_nbytes: usize,
+    pub received_nbytes: usize,
+    /// Only for transports that count their retransmits.
+    pub retransmits: Option<u64>,
+    /// State of a KCP stream at the end of the interval, not summed.
+    pub kcp: Option<KcpSample>,
+}
+
+/// Sender and receiver summaries, per direction seen from the client.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TestEnd {
+    pub send: DirectionSummary,
+    pub recv: DirectionSummary,
+    /// Client side latency of every stream of a request/response or
+    /// connection rate test, empty for other tests.
+    pub latency: Vec<LatencyStats>,
+    /// Client side connection setup time of every stream of a connection
+    /// rate test, empty for other tests.
+    pub connect: Vec<LatencyStats>,
+}
+
+/// Summaries of every stream in one direction, plus their sum.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DirectionSummary {
+    pub streams: Vec<StreamSummary>,
+    pub sum: StreamSummary,
+}
+
+/// Both ends' view of one stream in one direction. An end that did not take
+/// part in the direction has no summary.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct StreamSummary {
+    pub sender: Option<ResultSummary>,
+    pub receiver: Option<ResultSummary>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ResultSummary {
+    pub nbytes: usize,
+    pub seconds: f64,
+    pub bits_per_second: f64,
+    /// What this end moved on the wire, retransmits included, for a single
+    /// stream of a transport that tracks it.
+    pub wire: Option<WireStats>,
+    /// Last KCP sample this end took of a single stream.
+    pub kcp: Option<KcpSample>,
+    /// Datagram accounting of the receiving end of a single UDP stream.
+    pub udp: Option<UdpStats>,
+}
+
+impl From<StreamResult> for ResultSummary {
+    fn from(result: StreamResult) -> Self {
+        let seconds = result.elapsed.as_secs_f64();
+        ResultSummary {
+            nbytes: result.nbytes,
+            seconds,
+            bits_per_second: result.nbytes as f64 * 8. / seconds,
+            wire: None,
+            kcp: None,
+            udp: None,
+        }
+    }
+}
+
+/// Writes `message` as a big-endian `u32` length followed by its bincode
+/// encoding.
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let payload = bincode::serialize(message)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    writer.write_all(&(payload.len() as u32).to_be_bytes()[..])?;
+    writer.write_all(&payload[..])?;
+    writer.flush()
+}
+
+/// Reads a message written by `write_message`.
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> io::Result<T> {
+    decode(&read_payload(reader)?[..])
+}
+
+/// Reads the `PerfRequest` of a client. A request of another protocol
+/// version, whose layout may differ, is not decoded and yields `Err` with
+/// its version.
+pub fn read_request<R: Read>(reader: &mut R) -> io::Result<Result<PerfRequest, u32>> {
+    let payload = read_payload(reader)?;
+    if payload.len() < 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request has no protocol version",
+        ));
+    }
+    // bincode writes the leading `u32` as four little-endian bytes.
+    let version = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]);
+    if version != PROTOCOL_VERSION {
+        return Ok(Err(version));
+    }
+    decode(&payload[..]).map(Ok)
+}
+
+fn read_payload<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len[..])?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("control message of {} bytes is too large", len),
+        ));
+    }
+
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload[..])?;
+    Ok(payload)
+}
+
+fn decode<T: DeserializeOwned>(payload: &[u8]) -> io::Result<T> {
+    bincode::deserialize(payload).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_round_trip() {
+        let request = PerfRequest {
+            version: PROTOCOL_VERSION,
+            work_type: WorkType::Send,
+            transport: TransportKind::Kcp,
+            bucket_size: 32768,
+            length: TestLength::Time(Duration::from_secs(10)),
+            nstreams: 2,
+            bitrate: Some(2_000_000_000),
+            kernel_pacing: true,
+            kcp: KcpConfig::default(),
+            smoltcp_tcp: SmoltcpTcpConfig::default(),
+        };
+        let mut buf = Vec::new();
+        write_message(&mut buf, &request).unwrap();
+
+        let decoded: PerfRequest = read_message(&mut &buf[..]).unwrap();
+        assert_eq!(decoded.work_type, WorkType::Send);
+        assert_eq!(decoded.transport, TransportKind::Kcp);
+        assert_eq!(decoded.bucket_size, 32768);
+        assert_eq!(decoded.length, TestLength::Time(Duration::from_secs(10)));
+        assert_eq!(decoded.nstreams, 2);
+        assert_eq!(decoded.bitrate, Some(2_000_000_000));
+        assert_eq!(decoded.pacing_rate(), Some(250_000_000));
+        assert_eq!(decoded.kcp, KcpConfig::default());
+    }
+
+    #[test]
+    fn request_of_another_version_is_not_decoded() {
+        // Shaped like a version 15 request, which had no fast resend option.
+        #[derive(Serialize)]
+        struct OldRequest {
+            version: u32,
+            work_type: WorkType,
+            transport: TransportKind,
+            bucket_size: usize,
+        }
+        let request = OldRequest {
+            version: 15,
+            work_type: WorkType::Send,
+            transport: TransportKind::Tcp,
+            bucket_size: 1024,
+        };
+        let mut buf = Vec::new();
+        write_message(&mut buf, &request).unwrap();
+
+        assert_eq!(read_request(&mut &buf[..]).unwrap().unwrap_err(), 15);
+    }
 }
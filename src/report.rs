@@ -0,0 +1,747 @@
+use crate::proto::{
+    DirectionSummary, HostInfo, IntervalResult, IntervalStream, JsonReport, PerfRequest,
+    ResultSummary, StreamSummary, TestEnd, TestStart, WorkType,
+};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bytes moved by one stream and how long it took.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct StreamResult {
+    pub nbytes: usize,
+    pub elapsed: Duration,
+}
+
+impl StreamResult {
+    pub fn gib_per_sec(&self) -> f64 {
+        gib_per_sec(self.nbytes, self.elapsed)
+    }
+}
+
+/// What a stream moved on the wire, for transports that can tell it apart
+/// from the application bytes they deliver.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct WireStats {
+    pub send_ndatagrams: usize,
+    pub send_nbytes: usize,
+    /// Payload of every data segment sent, retransmissions included.
+    pub send_payload_nbytes: usize,
+    pub recv_ndatagrams: usize,
+    pub recv_nbytes: usize,
+    /// Payload of every data segment received, duplicates included.
+    pub recv_payload_nbytes: usize,
+    /// Data segments sent more than once.
+    pub send_retransmits: usize,
+    /// Sum of the round trip samples taken from acks, in milliseconds.
+    pub rtt_sum_ms: u64,
+    pub rtt_nsamples: u64,
+}
+
+impl WireStats {
+    pub fn mean_rtt(&self) -> Option<Duration> {
+        if self.rtt_nsamples == 0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            self.rtt_sum_ms as f64 / self.rtt_nsamples as f64 / 1000.,
+        ))
+    }
+
+    /// Data segment payload sent per application byte, above one when
+    /// segments had to be retransmitted.
+    pub fn send_amplification(&self, app_nbytes: usize) -> f64 {
+        self.send_payload_nbytes as f64 / app_nbytes as f64
+    }
+
+    /// Wire bytes received per application byte delivered, headers and acks
+    /// included.
+    pub fn recv_overhead(&self, app_nbytes: usize) -> f64 {
+        self.recv_nbytes as f64 / app_nbytes as f64
+    }
+
+    /// Data segment payload received per application byte delivered, above
+    /// one when duplicates arrived.
+    pub fn recv_amplification(&self, app_nbytes: usize) -> f64 {
+        self.recv_payload_nbytes as f64 / app_nbytes as f64
+    }
+}
+
+/// Datagram accounting of the receiving end of a UDP stream.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct UdpStats {
+    /// Every data datagram that arrived, duplicates included.
+    pub received: u64,
+    pub lost: u64,
+    pub duplicates: u64,
+    /// Datagrams that arrived after a later one.
+    pub out_of_order: u64,
+    /// RFC 3550 interarrival jitter, in seconds.
+    pub jitter: f64,
+}
+
+impl UdpStats {
+    /// Fraction of the datagrams sent that never arrived.
+    pub fn loss(&self) -> f64 {
+        let unique = self.received - self.duplicates;
+        if unique + self.lost == 0 {
+            return 0.;
+        }
+        self.lost as f64 / (unique + self.lost) as f64
+    }
+}
+
+/// Latency distribution of operations repeated one after another, such as
+/// the transactions of a request/response stream. Times are in seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct LatencyStats {
+    pub count: usize,
+    /// Operations per second over the whole stream.
+    pub rate: f64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub p999: f64,
+    pub max: f64,
+}
+
+impl LatencyStats {
+    /// Summarizes the latencies in `samples`, sorting them, of operations
+    /// that took `elapsed` in total. `None` if there are no samples.
+    pub fn from_samples(samples: &mut [Duration], elapsed: Duration) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+        let count = samples.len();
+        // Nearest-rank percentile, in thousandths.
+        let percentile = |permille: usize| samples[(count * permille).div_ceil(1000) - 1];
+        let total: Duration = samples.iter().sum();
+        Some(LatencyStats {
+            count,
+            rate: count as f64 / elapsed.as_secs_f64(),
+            mean: total.as_secs_f64() / count as f64,
+            p50: percentile(500).as_secs_f64(),
+            p90: percentile(900).as_secs_f64(),
+            p99: percentile(990).as_secs_f64(),
+            p999: percentile(999).as_secs_f64(),
+            max: samples[count - 1].as_secs_f64(),
+        })
+    }
+}
+
+/// Internal state of a KCP session at one point in time.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct KcpSample {
+    pub srtt_ms: u32,
+    pub rto_ms: u32,
+    /// Segments waiting to be sent or acknowledged.
+    pub wait_snd: usize,
+    /// Deepest `wait_snd` seen so far.
+    pub max_wait_snd: usize,
+    pub retransmits: usize,
+    /// Segments sent but not acknowledged yet, as far as the congestion and
+    /// remote windows let them out.
+    pub inflight: u32,
+    /// Receive window this end last advertised.
+    pub rcv_wnd: u16,
+    /// Receive window last advertised by the peer.
+    pub remote_wnd: u16,
+}
+
+/// Takes a `KcpSample` of a live session, or `None` once it is gone.
+pub type KcpSampler = Box<dyn Fn() -> Option<KcpSample> + Send>;
+
+/// Result of one stream in each direction it carried data.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct StreamReport {
+    pub sent: Option<StreamResult>,
+    pub received: Option<StreamResult>,
+    pub wire: Option<WireStats>,
+    /// Last KCP sample, taken when the stream ended.
+    pub kcp: Option<KcpSample>,
+    pub udp: Option<UdpStats>,
+    /// Transactions of the requesting end of a request/response stream, or
+    /// whole connections of a connection rate stream.
+    pub latency: Option<LatencyStats>,
+    /// Time `connect` took for each connection of a connection rate stream.
+    pub connect: Option<LatencyStats>,
+}
+
+/// Both ends' view of a finished test, stream by stream in connection order.
+#[derive(Debug, Clone, Default)]
+pub struct TestReport {
+    pub client: Vec<StreamReport>,
+    pub server: Vec<StreamReport>,
+    /// Client side interval reports, if any were asked for.
+    pub intervals: Vec<IntervalResult>,
+}
+
+impl TestReport {
+    /// Sender and receiver results of stream `index` for data flowing from
+    /// the client to the server.
+    pub fn upload(&self, index: usize) -> (Option<StreamResult>, Option<StreamResult>) {
+        (
+            self.client.get(index).and_then(|report| report.sent),
+            self.server.get(index).and_then(|report| report.received),
+        )
+    }
+
+    /// Sender and receiver results of stream `index` for data flowing from
+    /// the server to the client.
+    pub fn download(&self, index: usize) -> (Option<StreamResult>, Option<StreamResult>) {
+        (
+            self.server.get(index).and_then(|report| report.sent),
+            self.client.get(index).and_then(|report| report.received),
+        )
+    }
+
+    pub fn nstreams(&self) -> usize {
+        std::cmp::max(self.client.len(), self.server.len())
+    }
+}
+
+/// Running totals of one stream, updated by the engine as buckets move and
+/// sampled by the interval reporter from another thread.
+#[derive(Default)]
+pub struct StreamCounters {
+    sent_nbytes: AtomicUsize,
+    received_nbytes: AtomicUsize,
+    retransmits: AtomicU64,
+    has_retransmits: AtomicBool,
+    /// Only called on snapshots, to keep KCP's locks off the data path.
+    kcp: Mutex<Option<KcpSampler>>,
+}
+
+/// Point-in-time copy of `StreamCounters`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CounterSnapshot {
+    pub sent_nbytes: usize,
+    pub received_nbytes: usize,
+    pub retransmits: Option<u64>,
+    pub kcp: Option<KcpSample>,
+}
+
+impl StreamCounters {
+    pub fn add_sent(&self, nbytes: usize) {
+        self.sent_nbytes.fetch_add(nbytes, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, nbytes: usize) {
+        self.received_nbytes.fetch_add(nbytes, Ordering::Relaxed);
+    }
+
+    /// Records the transport's total retransmit count, if it reports one.
+    pub fn set_retransmits(&self, retransmits: Option<u64>) {
+        if let Some(retransmits) = retransmits {
+            self.retransmits.store(retransmits, Ordering::Relaxed);
+            self.has_retransmits.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_kcp_sampler(&self, sampler: Option<KcpSampler>) {
+        *self.kcp.lock().unwrap() = sampler;
+    }
+
+    pub fn snapshot(&self) -> CounterSnapshot {
+        CounterSnapshot {
+            sent_nbytes: self.sent_nbytes.load(Ordering::Relaxed),
+            received_nbytes: self.received_nbytes.load(Ordering::Relaxed),
+            retransmits: if self.has_retransmits.load(Ordering::Relaxed) {
+                Some(self.retransmits.load(Ordering::Relaxed))
+            } else {
+                None
+            },
+            kcp: self
+                .kcp
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(|sample| sample()),
+        }
+    }
+}
+
+/// Measures what every stream moved since the previous call, like iperf3's
+/// interval reports.
+pub struct IntervalReporter {
+    streams: Vec<Arc<StreamCounters>>,
+    start: Instant,
+    last_time: Duration,
+    last: Vec<CounterSnapshot>,
+}
+
+impl IntervalReporter {
+    pub fn new(streams: Vec<Arc<StreamCounters>>, start: Instant) -> Self {
+        let last = vec![CounterSnapshot::default(); streams.len()];
+        IntervalReporter {
+            streams,
+            start,
+            last_time: Duration::from_secs(0),
+            last,
+        }
+    }
+
+    /// Returns what every stream moved since the previous report, or `None`
+    /// if no time has passed.
+    pub fn report(&mut self) -> Option<IntervalResult> {
+        let now = self.start.elapsed();
+        if now == self.last_time {
+            return None;
+        }
+
+        let mut sum = IntervalStream::default();
+        let mut streams = Vec::new();
+        for (counters, last) in self.streams.iter().zip(self.last.iter_mut()) {
+            let current = counters.snapshot();
+            let delta = IntervalStream {
+                sent_nbytes: current.sent_nbytes - last.sent_nbytes,
+                received_nbytes: current.received_nbytes - last.received_nbytes,
+                retransmits: current
+                    .retransmits
+                    .map(|total| total - last.retransmits.unwrap_or(0)),
+                kcp: current.kcp,
+            };
+            sum.sent_nbytes += delta.sent_nbytes;
+            sum.received_nbytes += delta.received_nbytes;
+            sum.retransmits = match (sum.retransmits, delta.retransmits) {
+                (None, None) => None,
+                (sum, delta) => Some(sum.unwrap_or(0) + delta.unwrap_or(0)),
+            };
+            streams.push(delta);
+            *last = current;
+        }
+
+        let result = IntervalResult {
+            start: self.last_time.as_secs_f64(),
+            end: now.as_secs_f64(),
+            streams,
+            sum,
+        };
+        self.last_time = now;
+        Some(result)
+    }
+}
+
+/// Prints one line per stream and direction of `work_type`, plus sums when
+/// there are several streams.
+pub fn print_interval(result: &IntervalResult, work_type: WorkType) {
+    let interval = format!("{:.2}-{:.2}", result.start, result.end);
+    let elapsed = Duration::from_secs_f64(result.end - result.start);
+    for (index, stream) in result.streams.iter().enumerate() {
+        print_interval_stream(&interval, &index.to_string(), stream, elapsed, work_type);
+    }
+    if result.streams.len() > 1 {
+        print_interval_stream(&interval, "sum", &result.sum, elapsed, work_type);
+    }
+}
+
+fn print_interval_stream(
+    interval: &str,
+    stream: &str,
+    delta: &IntervalStream,
+    elapsed: Duration,
+    work_type: WorkType,
+) {
+    if work_type != WorkType::Recv {
+        let retransmits = match delta.retransmits {
+            Some(retransmits) => format!(" retransmits={}", retransmits),
+            None => String::new(),
+        };
+        println!(
+            "interval={} stream={} direction=send nbytes={} bitrate={}{}",
+            interval,
+            stream,
+            delta.sent_nbytes,
+            format_bitrate(delta.sent_nbytes, elapsed),
+            retransmits
+        );
+    }
+    if work_type != WorkType::Send {
+        println!(
+            "interval={} stream={} direction=recv nbytes={} bitrate={}",
+            interval,
+            stream,
+            delta.received_nbytes,
+            format_bitrate(delta.received_nbytes, elapsed)
+        );
+    }
+    if let Some(sample) = delta.kcp {
+        println!(
+            "interval={} stream={}{}",
+            interval,
+            stream,
+            format_kcp_sample(&sample)
+        );
+    }
+}
+
+fn format_kcp_sample(sample: &KcpSample) -> String {
+    format!(
+        " kcp_srtt_ms={} kcp_rto_ms={} kcp_wait_snd={} kcp_max_wait_snd={} kcp_retransmits={} kcp_inflight={} kcp_rcv_wnd={} kcp_remote_wnd={}",
+        sample.srtt_ms,
+        sample.rto_ms,
+        sample.wait_snd,
+        sample.max_wait_snd,
+        sample.retransmits,
+        sample.inflight,
+        sample.rcv_wnd,
+        sample.remote_wnd
+    )
+}
+
+/// Throughput in GiB/s.
+pub fn gib_per_sec(nbytes: usize, elapsed: Duration) -> f64 {
+    nbytes as f64 / 1024_f64.powi(3) / elapsed.as_secs_f64()
+}
+
+/// Throughput in bits per second, scaled to the largest decimal unit that
+/// keeps it at one or above, like `9.41Gbit/s`.
+pub fn format_bitrate(nbytes: usize, elapsed: Duration) -> String {
+    const UNITS: [&str; 4] = ["bit/s", "Kbit/s", "Mbit/s", "Gbit/s"];
+
+    let mut bitrate = nbytes as f64 * 8. / elapsed.as_secs_f64();
+    let mut unit = 0;
+    while bitrate >= 1000. && unit < UNITS.len() - 1 {
+        bitrate /= 1000.;
+        unit += 1;
+    }
+    format!("{:.2}{}", bitrate, UNITS[unit])
+}
+
+/// Adds up the results of several streams; the elapsed time is the longest.
+pub fn sum_results<I: IntoIterator<Item = StreamResult>>(results: I) -> Option<StreamResult> {
+    results.into_iter().fold(None, |sum, result| {
+        let sum = sum.unwrap_or_default();
+        Some(StreamResult {
+            nbytes: sum.nbytes + result.nbytes,
+            elapsed: std::cmp::max(sum.elapsed, result.elapsed),
+        })
+    })
+}
+
+/// Prints what the server end of one stream sent and received, directions
+/// named from the client's point of view like in `print_test_report`.
+pub fn print_report(stream: &str, report: &StreamReport) {
+    print_result(stream, "send", "receiver", report.received);
+    print_result(stream, "recv", "sender", report.sent);
+}
+
+/// Prints what one end of a stream moved on the wire next to the goodput,
+/// protocol overhead and retransmission amplification it got for it.
+pub fn print_wire(stream: &str, side: &str, report: &StreamReport) {
+    let wire = match report.wire {
+        Some(wire) => wire,
+        None => return,
+    };
+
+    let mut line = format!(
+        "stream={} side={} wire_send_ndatagrams={} wire_send_nbytes={} wire_recv_ndatagrams={} wire_recv_nbytes={}",
+        stream,
+        side,
+        wire.send_ndatagrams,
+        wire.send_nbytes,
+        wire.recv_ndatagrams,
+        wire.recv_nbytes
+    );
+    if let Some(sent) = report.sent {
+        line += &format!(
+            " send_amplification={} retransmits={}",
+            wire.send_amplification(sent.nbytes),
+            wire.send_retransmits
+        );
+    }
+    if let Some(rtt) = wire.mean_rtt() {
+        line += &format!(" rtt={}", rtt.as_secs_f64());
+    }
+    if let Some(received) = report.received {
+        line += &format!(
+            " goodput={} recv_overhead={} recv_amplification={}",
+            received.gib_per_sec(),
+            wire.recv_overhead(received.nbytes),
+            wire.recv_amplification(received.nbytes)
+        );
+    }
+    if let Some(sample) = report.kcp {
+        line += &format_kcp_sample(&sample);
+    }
+    println!("{}", line);
+}
+
+/// Prints the datagram loss, reordering and jitter one end of a UDP stream
+/// saw as the receiver.
+pub fn print_udp(stream: &str, side: &str, report: &StreamReport) {
+    if let Some(udp) = report.udp {
+        println!(
+            "stream={} side={} datagrams={} lost={} loss={} duplicates={} out_of_order={} jitter={}",
+            stream,
+            side,
+            udp.received,
+            udp.lost,
+            udp.loss(),
+            udp.duplicates,
+            udp.out_of_order,
+            udp.jitter
+        );
+    }
+}
+
+/// Prints the transaction rate and latency percentiles of the requesting end
+/// of a request/response stream, and its connection setup times in a
+/// connection rate test.
+pub fn print_latency(stream: &str, side: &str, report: &StreamReport) {
+    if let Some(latency) = report.latency {
+        print_latency_stats(stream, side, "transactions", "latency", &latency);
+    }
+    if let Some(connect) = report.connect {
+        print_latency_stats(stream, side, "connections", "connect", &connect);
+    }
+}
+
+fn print_latency_stats(stream: &str, side: &str, count: &str, prefix: &str, stats: &LatencyStats) {
+    println!(
+        "stream={} side={} {count}={} {count}_per_sec={} {prefix}_mean={} {prefix}_p50={} {prefix}_p90={} {prefix}_p99={} {prefix}_p999={} {prefix}_max={}",
+        stream,
+        side,
+        stats.count,
+        stats.rate,
+        stats.mean,
+        stats.p50,
+        stats.p90,
+        stats.p99,
+        stats.p999,
+        stats.max,
+        count = count,
+        prefix = prefix
+    );
+}
+
+fn print_result(stream: &str, direction: &str, side: &str, result: Option<StreamResult>) {
+    if let Some(result) = result {
+        println!(
+            "stream={} direction={} side={} nbytes={} elapsed={} speed={}",
+            stream,
+            direction,
+            side,
+            result.nbytes,
+            result.elapsed.as_secs_f64(),
+            result.gib_per_sec()
+        );
+    }
+}
+
+/// Prints sender and receiver numbers of every stream and their sums.
+/// Directions are named from the client's point of view.
+pub fn print_test_report(report: &TestReport) {
+    print_direction(report, "send", TestReport::upload);
+    print_direction(report, "recv", TestReport::download);
+    for (index, stream) in report.client.iter().enumerate() {
+        print_wire(&index.to_string(), "client", stream);
+        print_udp(&index.to_string(), "client", stream);
+        print_latency(&index.to_string(), "client", stream);
+    }
+    let latencies = report
+        .client
+        .iter()
+        .filter_map(|stream| stream.latency)
+        .collect::<Vec<_>>();
+    if latencies.len() > 1 {
+        println!(
+            "stream=sum side=client transactions={} transactions_per_sec={}",
+            latencies.iter().map(|latency| latency.count).sum::<usize>(),
+            latencies.iter().map(|latency| latency.rate).sum::<f64>()
+        );
+    }
+    for (index, stream) in report.server.iter().enumerate() {
+        print_wire(&index.to_string(), "server", stream);
+        print_udp(&index.to_string(), "server", stream);
+    }
+}
+
+fn print_direction<F>(report: &TestReport, direction: &str, results: F)
+where
+    F: Fn(&TestReport, usize) -> (Option<StreamResult>, Option<StreamResult>),
+{
+    let nstreams = report.nstreams();
+    let mut senders = Vec::new();
+    let mut receivers = Vec::new();
+    for index in 0..nstreams {
+        let (sender, receiver) = results(report, index);
+        print_result(&index.to_string(), direction, "sender", sender);
+        print_result(&index.to_string(), direction, "receiver", receiver);
+        senders.extend(sender);
+        receivers.extend(receiver);
+    }
+    if nstreams > 1 {
+        print_result("sum", direction, "sender", sum_results(senders));
+        print_result("sum", direction, "receiver", sum_results(receivers));
+    }
+}
+
+/// Builds the `--json` document of a finished test.
+pub fn json_report(
+    host: HostInfo,
+    server: SocketAddr,
+    timestamp: u64,
+    request: PerfRequest,
+    report: &TestReport,
+) -> JsonReport {
+    JsonReport {
+        start: TestStart {
+            host,
+            server,
+            timestamp,
+            request,
+        },
+        intervals: report.intervals.clone(),
+        end: TestEnd {
+            send: summarize_direction(report, TestReport::upload, &report.client, &report.server),
+            recv: summarize_direction(report, TestReport::download, &report.server, &report.client),
+            latency: report
+                .client
+                .iter()
+                .filter_map(|stream| stream.latency)
+                .collect(),
+            connect: report
+                .client
+                .iter()
+                .filter_map(|stream| stream.connect)
+                .collect(),
+        },
+    }
+}
+
+/// Summarizes one direction, `sender_ends` and `receiver_ends` being the
+/// reports of the ends that sent and received it.
+fn summarize_direction<F>(
+    report: &TestReport,
+    results: F,
+    sender_ends: &[StreamReport],
+    receiver_ends: &[StreamReport],
+) -> DirectionSummary
+where
+    F: Fn(&TestReport, usize) -> (Option<StreamResult>, Option<StreamResult>),
+{
+    let mut summary = DirectionSummary::default();
+    let mut senders = Vec::new();
+    let mut receivers = Vec::new();
+    for index in 0..report.nstreams() {
+        let (sender, receiver) = results(report, index);
+        summary.streams.push(StreamSummary {
+            sender: sender.map(|result| summarize_end(result, sender_ends.get(index))),
+            receiver: receiver.map(|result| summarize_end(result, receiver_ends.get(index))),
+        });
+        senders.extend(sender);
+        receivers.extend(receiver);
+    }
+    summary.sum = StreamSummary {
+        sender: sum_results(senders).map(Into::into),
+        receiver: sum_results(receivers).map(Into::into),
+    };
+    summary
+}
+
+fn summarize_end(result: StreamResult, end: Option<&StreamReport>) -> ResultSummary {
+    let mut summary = ResultSummary::from(result);
+    if let Some(end) = end {
+        summary.wire = end.wire;
+        summary.kcp = end.kcp;
+        summary.udp = end.udp;
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_latency_percentiles() {
+        let mut samples = (1..=1000)
+            .rev()
+            .map(Duration::from_millis)
+            .collect::<Vec<_>>();
+        let latency = LatencyStats::from_samples(&mut samples, Duration::from_secs(500)).unwrap();
+        assert_eq!(latency.count, 1000);
+        assert_eq!(latency.rate, 2.);
+        assert!((latency.mean - 0.5005).abs() < 1e-9);
+        assert_eq!(latency.p50, 0.5);
+        assert_eq!(latency.p90, 0.9);
+        assert_eq!(latency.p99, 0.99);
+        assert_eq!(latency.p999, 0.999);
+        assert_eq!(latency.max, 1.);
+        assert!(LatencyStats::from_samples(&mut [], Duration::from_secs(1)).is_none());
+    }
+
+    #[test]
+    fn formats_bitrate_with_scaled_units() {
+        let second = Duration::from_secs(1);
+        assert_eq!(format_bitrate(100, second), "800.00bit/s");
+        assert_eq!(format_bitrate(125_000, second), "1.00Mbit/s");
+        assert_eq!(
+            format_bitrate(1_250_000_000, Duration::from_secs(2)),
+            "5.00Gbit/s"
+        );
+        assert_eq!(format_bitrate(1_250_000_000_000, second), "10000.00Gbit/s");
+    }
+
+    #[test]
+    fn json_report_of_udp_run_keeps_datagram_accounting() {
+        let elapsed = Duration::from_secs(2);
+        let udp = UdpStats {
+            received: 1000,
+            lost: 10,
+            duplicates: 2,
+            out_of_order: 3,
+            jitter: 0.0005,
+        };
+        let report = TestReport {
+            client: vec![StreamReport {
+                sent: Some(StreamResult {
+                    nbytes: 1_010_000,
+                    elapsed,
+                }),
+                ..StreamReport::default()
+            }],
+            server: vec![StreamReport {
+                received: Some(StreamResult {
+                    nbytes: 998_000,
+                    elapsed,
+                }),
+                udp: Some(udp),
+                ..StreamReport::default()
+            }],
+            intervals: Vec::new(),
+        };
+        let config = crate::client::ClientConfig {
+            transport: crate::transport::TransportKind::Udp,
+            ..Default::default()
+        };
+        let host = HostInfo {
+            hostname: "client".into(),
+            system: "Linux".into(),
+            release: "6.1".into(),
+            machine: "x86_64".into(),
+        };
+        let server = SocketAddr::new([127, 0, 0, 1].into(), 5201);
+        let json = json_report(host, server, 0, config.perf_request(), &report);
+
+        let stream = json.end.send.streams[0];
+        let sender = stream.sender.unwrap();
+        assert!(sender.udp.is_none());
+        assert_eq!(sender.bits_per_second, 4_040_000.);
+        let receiver = stream.receiver.unwrap();
+        let received = receiver.udp.unwrap();
+        assert_eq!(received.received, 1000);
+        assert_eq!(received.lost, 10);
+        assert_eq!(received.duplicates, 2);
+        assert_eq!(received.out_of_order, 3);
+        assert_eq!(received.jitter, 0.0005);
+        assert!(receiver.wire.is_none() && receiver.kcp.is_none());
+        assert!(json.end.send.sum.receiver.unwrap().udp.is_none());
+        assert!(json.end.recv.streams[0].sender.is_none());
+    }
+}
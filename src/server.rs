@@ -1,114 +1,447 @@
-pub mod proto;
-
-use crate::proto::{PerfRequest, WorkType};
-use argparse::{ArgumentParser, Store, StoreTrue};
-use std::io;
-use std::io::{Read, Write};
-use std::net::{Shutdown, TcpListener};
-use std::time::{Duration, Instant, SystemTime};
-
-
-pub fn nonblocking_write_all(stream: &mut std::net::TcpStream, mut buf: &[u8]) -> io::Result<()> {
-    while !buf.is_empty() {
-        match stream.write(buf) {
-            Ok(0) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::WriteZero,
-                    "failed to write whole buffer",
-                ));
+use crate::engine::{self, ConnectionStreams};
+use crate::proto::{
+    self, PerfRequest, PerfResponse, PerfResults, TestLength, WorkType, DEFAULT_PORT,
+    PROTOCOL_VERSION,
+};
+use crate::report::{self, StreamReport};
+use crate::transport::kcp::{self, KcpTransportListener};
+use crate::transport::smoltcp::{SmoltcpTcpTransportListener, SmoltcpUdpTransportListener};
+use crate::transport::stack::Stack;
+use crate::transport::tcp::TcpTransportListener;
+use crate::transport::udp::{self, UdpTransportListener};
+use crate::transport::{Listener, TransportKind, TransportOptions};
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const MAX_BUCKET_SIZE: usize = 1024 * 1024 * 1024;
+/// Most streams a test may open.
+pub const MAX_STREAMS: usize = 128;
+/// Most bucket memory the streams of a test may take together.
+pub const MAX_TOTAL_BUCKET_SIZE: usize = 2 * MAX_BUCKET_SIZE;
+/// Longest the server waits on a client: for its request, for each of its
+/// streams and for a running stream to make progress.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the server looks at the control connection while it waits for
+/// streams, to end the session as soon as the client goes away.
+const CONTROL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub address: IpAddr,
+    /// Port of the control connections.
+    pub port: u16,
+    /// Largest bucket the server accepts from a client.
+    pub bucket_size: usize,
+    /// User-space stack the smoltcp transports run on.
+    pub stack: Option<Arc<Stack>>,
+    /// Keep stdout free for a client in the same process: no request lines
+    /// and no per-stream reports.
+    pub quiet: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: "0.0.0.0".parse().unwrap(),
+            port: DEFAULT_PORT,
+            bucket_size: MAX_BUCKET_SIZE,
+            stack: None,
+            quiet: false,
+        }
+    }
+}
+
+/// Clears the busy flag of the server when a session ends, however it ends.
+struct SessionGuard(Arc<AtomicBool>);
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Accepts control connections and runs the test each of them requests, one
+/// test at a time; clients arriving during a test are told the server is
+/// busy. A failed session is reported and the server moves on to the next
+/// one, so this only returns if the control port cannot be bound.
+pub fn run(config: &ServerConfig) -> io::Result<()> {
+    println!("listen_address={:?}", config.address);
+    let listener = TcpListener::bind(SocketAddr::new(config.address, config.port))?;
+    println!("Listening on {:?}", listener.local_addr()?);
+
+    let busy = Arc::new(AtomicBool::new(false));
+    loop {
+        let mut control = match listener.accept() {
+            Ok((control, _)) => control,
+            Err(err) => {
+                println!("session failed: {}", err);
+                continue;
             }
-            Ok(n) => buf = &buf[n..],
-            Err(ref e)
-                if e.kind() == io::ErrorKind::Interrupted
-                    || e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(e) => return Err(e),
+        };
+        if busy.swap(true, Ordering::AcqRel) {
+            std::thread::spawn(move || {
+                if let Err(err) = reject_busy(&mut control) {
+                    println!("session failed: {}", err);
+                }
+            });
+            continue;
         }
-        std::thread::yield_now();
+
+        let guard = SessionGuard(busy.clone());
+        let config = config.clone();
+        std::thread::spawn(move || {
+            let _guard = guard;
+            if let Err(err) = handle_session(&config, &mut control) {
+                println!("session failed: {}", err);
+            }
+        });
     }
-    Ok(())
 }
 
-pub fn nonblocking_read_exact(
-    stream: &mut std::net::TcpStream,
-    mut buf: &mut [u8],
-) -> io::Result<()> {
-    while !buf.is_empty() {
-        match stream.read(buf) {
-            Ok(0) => break,
-            Ok(n) => {
-                let tmp = buf;
-                buf = &mut tmp[n..];
+/// Turns away a client that arrived while another test runs.
+fn reject_busy(control: &mut TcpStream) -> io::Result<()> {
+    // The request is read first, as closing the connection with it unread
+    // could reset it before the client reads the rejection.
+    control.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let _ = proto::read_request(control)?;
+    println!("peer={:?}, rejected: server busy", control.peer_addr()?);
+    proto::write_message(
+        control,
+        &PerfResponse::Reject {
+            reason: "server busy".to_string(),
+        },
+    )
+}
+
+/// Accepts one control connection on `listener` and runs the test it requests.
+pub fn serve_one(config: &ServerConfig, listener: &TcpListener) -> io::Result<()> {
+    let (mut control, _) = listener.accept()?;
+    handle_session(config, &mut control)
+}
+
+fn handle_session(config: &ServerConfig, control: &mut TcpStream) -> io::Result<()> {
+    control.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let request = match proto::read_request(control)? {
+        Ok(request) => request,
+        Err(version) => {
+            let reason = format!(
+                "protocol version {} is not supported, expected {}",
+                version, PROTOCOL_VERSION
+            );
+            if !config.quiet {
+                println!("rejected: {}", reason);
+            }
+            return proto::write_message(control, &PerfResponse::Reject { reason });
+        }
+    };
+    if !config.quiet {
+        println!("peer={:?}, request={:?}", control.peer_addr()?, request);
+    }
+
+    if let Err(reason) = validate(config, &request) {
+        if !config.quiet {
+            println!("rejected: {}", reason);
+        }
+        return proto::write_message(control, &PerfResponse::Reject { reason });
+    }
+
+    match request.transport {
+        TransportKind::Tcp => serve::<TcpTransportListener>(config, control, &request),
+        TransportKind::Kcp => serve::<KcpTransportListener>(config, control, &request),
+        TransportKind::SmoltcpUdp => {
+            serve::<SmoltcpUdpTransportListener>(config, control, &request)
+        }
+        TransportKind::Udp => serve::<UdpTransportListener>(config, control, &request),
+        TransportKind::SmoltcpTcp => {
+            serve::<SmoltcpTcpTransportListener>(config, control, &request)
+        }
+    }
+}
+
+fn validate(config: &ServerConfig, request: &PerfRequest) -> Result<(), String> {
+    if request.bucket_size == 0 || request.bucket_size > config.bucket_size {
+        return Err(format!(
+            "bucket size must be between 1 and {} bytes",
+            config.bucket_size
+        ));
+    }
+    if request.length == TestLength::Time(Duration::from_secs(0)) {
+        return Err("test duration must be positive".to_string());
+    }
+    if request.nstreams == 0 || request.nstreams > MAX_STREAMS {
+        return Err(format!(
+            "number of streams must be between 1 and {}",
+            MAX_STREAMS
+        ));
+    }
+    if request.nstreams.saturating_mul(request.bucket_size) > MAX_TOTAL_BUCKET_SIZE {
+        return Err(format!(
+            "buckets of all streams must take at most {} bytes together",
+            MAX_TOTAL_BUCKET_SIZE
+        ));
+    }
+    if request.nstreams > 1 && !request.transport.supports_multiple_streams() {
+        return Err(format!("{} supports a single stream", request.transport));
+    }
+    if let Some(response_size) = request.work_type.response_size() {
+        validate_rr(config, request, response_size)?;
+    }
+    if let WorkType::Connect { .. } | WorkType::Accept { .. } = request.work_type {
+        match request.transport {
+            TransportKind::Tcp | TransportKind::SmoltcpTcp => {}
+            TransportKind::Kcp | TransportKind::SmoltcpUdp | TransportKind::Udp => {
+                return Err(format!(
+                    "{} does not support connection rate tests",
+                    request.transport
+                ))
             }
-            Err(ref e)
-                if e.kind() == io::ErrorKind::Interrupted
-                    || e.kind() == io::ErrorKind::WouldBlock => {}
-            Err(e) => return Err(e),
         }
-        std::thread::yield_now();
     }
-    if !buf.is_empty() {
-        Err(io::Error::new(
-            io::ErrorKind::UnexpectedEof,
-            "failed to fill whole buffer",
-        ))
-    } else {
-        Ok(())
+    if request.bitrate == Some(0) {
+        return Err("bitrate must be positive".to_string());
+    }
+    if request.kernel_pacing {
+        if request.bitrate.is_none() {
+            return Err("kernel pacing needs a bitrate".to_string());
+        }
+        if request.transport != TransportKind::Tcp {
+            return Err(format!(
+                "{} does not support kernel pacing",
+                request.transport
+            ));
+        }
+    }
+    let smoltcp = match request.transport {
+        TransportKind::SmoltcpUdp | TransportKind::SmoltcpTcp => true,
+        TransportKind::Tcp | TransportKind::Kcp | TransportKind::Udp => false,
+    };
+    if smoltcp && config.stack.is_none() {
+        return Err("the server has no smoltcp device".to_string());
+    }
+    if request.transport == TransportKind::SmoltcpTcp {
+        request.smoltcp_tcp.validate()?;
+    }
+    if request.transport == TransportKind::Kcp {
+        request.kcp.validate()?;
     }
+    Ok(())
 }
 
-fn main() {
-    let mut address = "0.0.0.0".to_string();
-    const BUCKET_SIZE: usize = 1 * (1024 as usize).pow(3);
-    {
-        // this block limits scope of borrows by ap.refer() method
-        let mut ap = ArgumentParser::new();
-        ap.set_description("tcp server.");
-        ap.refer(&mut address)
-            .add_option(&["--address"], Store, "Listening address");
-        ap.parse_args_or_exit();
+/// Requests and responses must each arrive in a single bucket, which on
+/// datagram transports means a single datagram.
+fn validate_rr(
+    config: &ServerConfig,
+    request: &PerfRequest,
+    response_size: usize,
+) -> Result<(), String> {
+    if response_size == 0 || response_size > config.bucket_size {
+        return Err(format!(
+            "response size must be between 1 and {} bytes",
+            config.bucket_size
+        ));
+    }
+    let max_message_size = match request.transport {
+        TransportKind::Udp => udp::MAX_PAYLOAD_SIZE,
+        TransportKind::SmoltcpUdp => match &config.stack {
+            Some(stack) => stack.max_datagram_size(),
+            None => return Ok(()),
+        },
+        TransportKind::Tcp | TransportKind::Kcp | TransportKind::SmoltcpTcp => return Ok(()),
+    };
+    if request.bucket_size.max(response_size) > max_message_size {
+        return Err(format!(
+            "{} requests and responses must fit in a datagram of {} bytes",
+            request.transport, max_message_size
+        ));
     }
+    Ok(())
+}
 
-    let mut workers = Vec::new();
-    let listen_address = address.clone();
-
-    println!("listen_address={:?}", listen_address);
-    let listen_address = format!("{}:0", listen_address);
-    // // let listen_to_address = format!("{}:0", *address);
-    let listener = TcpListener::bind(listen_address).unwrap();
-    let sockaddr = listener.local_addr().unwrap();
-
-    // let mut bucket: [u8; bucket_size] = [0; bucket_size];
-    println!("Listening on {:?}", sockaddr);
-    while match listener.accept() {
-        Ok((mut stream, _)) => {
-            // stream.set_nodelay(true).unwrap();
-            // stream.set_nonblocking(true).unwrap();
-
-            let mut bucket: Vec<u8> = vec![0; BUCKET_SIZE];
-            workers.push(std::thread::spawn(move || {
-                loop {
-                    let mut target_nbytes = BUCKET_SIZE.to_be_bytes();
-                    stream.read_exact(&mut target_nbytes[..]).unwrap();
-                    // nonblocking_read_exact(&mut stream, &mut target_nbytes[..]).unwrap();
-                    let target_nbytes = usize::from_be_bytes(target_nbytes);
-                    if target_nbytes == 0 {
-                        break
-                    }
-                    stream.read_exact(&mut bucket[..target_nbytes]).unwrap();
-                    // nonblocking_read_exact(&mut stream, &mut bucket[..target_nbytes])
-                    //     .unwrap();
-                }
-            }));
+fn serve<L: Listener + 'static>(
+    config: &ServerConfig,
+    control: &mut TcpStream,
+    request: &PerfRequest,
+) -> io::Result<()>
+where
+    L::Transport: 'static,
+{
+    let kcp_conv = kcp::allocate_convs(request.nstreams);
+    let options = TransportOptions {
+        bucket_size: request.bucket_size,
+        kcp_conv,
+        kcp_nconvs: request.nstreams,
+        kcp: request.kcp,
+        pacing_rate: request.pacing_rate(),
+        smoltcp_tcp: request.smoltcp_tcp,
+        stack: config.stack.clone(),
+        accept_timeout: Some(CONTROL_CHECK_INTERVAL),
+        peer_timeout: Some(peer_timeout(request)),
+    };
+    let listener = Arc::new(Mutex::new(L::bind(
+        &SocketAddr::new(config.address, 0),
+        &options,
+    )?));
+    let data_addr = listener.lock().unwrap().local_addr()?;
+    proto::write_message(
+        control,
+        &PerfResponse::Accept {
+            data_addr,
+            kcp_conv,
+        },
+    )?;
 
-            true
+    let streams = match request.work_type.reversed() {
+        WorkType::Accept { response_size } => {
+            accept_connections(listener.clone(), control, request, response_size)?
         }
-        Err(err) => {
-            println!("listener.accept failed, err={:?}", err);
-            false
+        work_type => accept_streams(&mut *listener.lock().unwrap(), control, request, work_type)?,
+    };
+
+    if !config.quiet {
+        for (index, report) in streams.iter().enumerate() {
+            report::print_report(&index.to_string(), report);
+            report::print_wire(&index.to_string(), "server", report);
+            report::print_udp(&index.to_string(), "server", report);
         }
-    } {}
+    }
+    proto::write_message(control, &PerfResults { streams })?;
+    // The client may still be waiting for the acks of its last segments,
+    // which only come while the listener is up.
+    wait_for_close(control, peer_timeout(request));
+    drop(listener);
+    Ok(())
+}
 
-    for worker in workers {
-        worker.join().unwrap();
+/// Waits up to `timeout` for the client to close the control connection,
+/// which it does once it has read its results.
+fn wait_for_close(control: &mut TcpStream, timeout: Duration) {
+    let mut byte = [0; 1];
+    if control.set_read_timeout(Some(timeout)).is_ok() {
+        let _ = control.read(&mut byte);
     }
 }
+
+/// How long a stream may go without progress before the server gives up on
+/// the client; a paced sender leaves a bucket's worth of time between buckets.
+fn peer_timeout(request: &PerfRequest) -> Duration {
+    let bucket_interval = match request.bitrate {
+        Some(bitrate) => request.bucket_size as f64 * 8. / bitrate as f64,
+        None => 0.,
+    };
+    CLIENT_TIMEOUT + Duration::from_secs_f64(bucket_interval)
+}
+
+/// Whether the client closed the control connection, which it leaves silent
+/// until the results arrive.
+fn control_closed(control: &TcpStream) -> io::Result<bool> {
+    control.set_nonblocking(true)?;
+    let mut byte = [0; 1];
+    let result = control.peek(&mut byte);
+    control.set_nonblocking(false)?;
+    match result {
+        Ok(nbytes) => Ok(nbytes == 0),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Accepts the next stream of the client behind `control`, giving up once
+/// the client closes the control connection or after `CLIENT_TIMEOUT`.
+fn accept_stream<L: Listener>(listener: &mut L, control: &TcpStream) -> io::Result<L::Transport> {
+    let start = Instant::now();
+    loop {
+        match listener.accept() {
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+            result => return result,
+        }
+        if control_closed(control)? {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "client closed the control connection",
+            ));
+        }
+        if start.elapsed() >= CLIENT_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no stream arrived for {:?}", CLIENT_TIMEOUT),
+            ));
+        }
+    }
+}
+
+/// Accepts the streams of a test, runs each on its own thread and returns
+/// their reports.
+fn accept_streams<L: Listener>(
+    listener: &mut L,
+    control: &TcpStream,
+    request: &PerfRequest,
+    work_type: WorkType,
+) -> io::Result<Vec<StreamReport>>
+where
+    L::Transport: 'static,
+{
+    let mut workers = Vec::new();
+    for _ in 0..request.nstreams {
+        let transport = match accept_stream(listener, control) {
+            Ok(transport) => transport,
+            Err(err) => {
+                // The streams already running end by themselves.
+                let _ = engine::join_all(workers);
+                return Err(err);
+            }
+        };
+        let bucket_size = request.bucket_size;
+        let length = request.length;
+        let bitrate = request.bitrate;
+
+        workers.push(std::thread::spawn(move || {
+            let mut bucket: Vec<u8> = vec![0; bucket_size];
+            engine::run_stream(
+                Box::new(transport),
+                work_type,
+                &mut bucket[..],
+                length,
+                bitrate,
+                Arc::default(),
+                || {},
+            )
+        }));
+    }
+    engine::join_all(workers)
+}
+
+/// Serves a connection rate test with one handler per client stream. The
+/// handlers take turns accepting on `listener` and each stops at the end
+/// marker of one client stream, so the number of threads stays the same
+/// however many connections the test makes. Returns the results of every
+/// client stream, which the requests name.
+fn accept_connections<L: Listener + 'static>(
+    listener: Arc<Mutex<L>>,
+    control: &TcpStream,
+    request: &PerfRequest,
+    response_size: usize,
+) -> io::Result<Vec<StreamReport>> {
+    let control = Arc::new(control.try_clone()?);
+    let streams = Arc::new(ConnectionStreams::new(request.nstreams));
+    let handlers = (0..request.nstreams)
+        .map(|_| {
+            let listener = listener.clone();
+            let control = control.clone();
+            let streams = streams.clone();
+            let request_size = engine::STREAM_INDEX_SIZE + request.bucket_size;
+            std::thread::spawn(move || {
+                engine::respond_connections(
+                    || accept_stream(&mut *listener.lock().unwrap(), &control),
+                    &mut vec![0; request_size][..],
+                    &vec![0; response_size][..],
+                    &streams,
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    engine::join_all(handlers)?;
+    Ok(streams.reports())
+}
@@ -0,0 +1,204 @@
+use crate::client::{self, ClientConfig};
+use crate::report::{sum_results, TestReport, WireStats};
+use crate::transport::kcp::KcpConfig;
+use crate::transport::TransportKind;
+use serde::Serialize;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Values tried for each swept KCP setting; every combination is one test.
+#[derive(Debug, Clone)]
+pub struct SweepConfig {
+    pub nodelay: Vec<bool>,
+    pub interval: Vec<u32>,
+    pub resend: Vec<u32>,
+    /// Used for both the send and the receive window.
+    pub wnd: Vec<u16>,
+    pub mtu: Vec<usize>,
+}
+
+impl Default for SweepConfig {
+    fn default() -> Self {
+        SweepConfig {
+            nodelay: vec![true, false],
+            interval: vec![10, 40],
+            resend: vec![0, 2],
+            wnd: vec![128, 1024],
+            mtu: vec![1400],
+        }
+    }
+}
+
+impl SweepConfig {
+    /// Every combination of the swept values, applied on top of `base`.
+    pub fn kcp_configs(&self, base: &KcpConfig) -> Vec<KcpConfig> {
+        let mut configs = Vec::new();
+        for &nodelay in &self.nodelay {
+            for &interval in &self.interval {
+                for &resend in &self.resend {
+                    for &wnd in &self.wnd {
+                        for &mtu in &self.mtu {
+                            // Without an override, so that `resend` is the
+                            // threshold the sessions run with.
+                            configs.push(KcpConfig {
+                                nodelay,
+                                interval,
+                                resend,
+                                fast_resend: None,
+                                snd_wnd: wnd,
+                                rcv_wnd: wnd,
+                                mtu,
+                                ..*base
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        configs
+    }
+}
+
+/// Parses a comma separated list of values such as `10,20,40`.
+pub fn parse_list<T: FromStr>(list: &str) -> Result<Vec<T>, String> {
+    list.split(',')
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| format!("bad value {:?} in {:?}", value, list))
+        })
+        .collect()
+}
+
+/// Outcome of the test of one KCP configuration.
+#[derive(Debug, Clone)]
+pub struct SweepResult {
+    pub kcp: KcpConfig,
+    /// Receiver side throughput of every stream and direction, in GiB/s.
+    pub goodput: f64,
+    /// Mean round trip seen by the senders.
+    pub rtt: Option<Duration>,
+    pub retransmits: usize,
+}
+
+impl SweepResult {
+    fn new(kcp: KcpConfig, report: &TestReport) -> Self {
+        let mut receivers = Vec::new();
+        for index in 0..report.nstreams() {
+            receivers.extend(report.upload(index).1);
+            receivers.extend(report.download(index).1);
+        }
+        let goodput = sum_results(receivers).map_or(0., |sum| sum.gib_per_sec());
+
+        let mut wire = WireStats::default();
+        for stream in report.client.iter().chain(report.server.iter()) {
+            if let Some(stream) = stream.wire {
+                wire.send_retransmits += stream.send_retransmits;
+                wire.rtt_sum_ms += stream.rtt_sum_ms;
+                wire.rtt_nsamples += stream.rtt_nsamples;
+            }
+        }
+
+        SweepResult {
+            kcp,
+            goodput,
+            rtt: wire.mean_rtt(),
+            retransmits: wire.send_retransmits,
+        }
+    }
+}
+
+/// Goodputs within about 2% of each other rank the same on goodput alone.
+const GOODPUT_STEPS_PER_E: f64 = 50.;
+
+/// Runs one KCP test per configuration of `sweep` against the server of
+/// `config` and returns the results, best first: the highest goodput, and
+/// among goodputs within about 2% the lowest RTT, then the fewest
+/// retransmits. A failed test is reported and left out of the ranking.
+pub fn run(config: &ClientConfig, sweep: &SweepConfig) -> Vec<SweepResult> {
+    let configs = sweep.kcp_configs(&config.kcp);
+    let mut results = Vec::new();
+    for (index, kcp) in configs.iter().enumerate() {
+        // Progress goes to stderr, as stdout may carry a --json document.
+        eprintln!("sweep={}/{} kcp={:?}", index + 1, configs.len(), kcp);
+        let test = ClientConfig {
+            transport: TransportKind::Kcp,
+            kcp: *kcp,
+            quiet: true,
+            ..config.clone()
+        };
+        match client::run(&test) {
+            Ok(report) => results.push(SweepResult::new(*kcp, &report)),
+            Err(err) => eprintln!("sweep={}/{} failed: {}", index + 1, configs.len(), err),
+        }
+    }
+
+    results.sort_by_key(|result| {
+        (
+            std::cmp::Reverse((result.goodput.ln() * GOODPUT_STEPS_PER_E).round() as i64),
+            result.rtt.unwrap_or(Duration::MAX),
+            result.retransmits,
+        )
+    });
+    results
+}
+
+/// One ranked result of `--json` output.
+#[derive(Serialize, Debug)]
+pub struct JsonSweepResult {
+    pub rank: usize,
+    pub kcp: KcpConfig,
+    pub goodput_gib_per_sec: f64,
+    pub rtt_ms: Option<f64>,
+    pub retransmits: usize,
+}
+
+/// The ranked results in the shape of the `--json` output.
+pub fn json_results(results: &[SweepResult]) -> Vec<JsonSweepResult> {
+    results
+        .iter()
+        .enumerate()
+        .map(|(rank, result)| JsonSweepResult {
+            rank: rank + 1,
+            kcp: result.kcp,
+            goodput_gib_per_sec: result.goodput,
+            rtt_ms: result.rtt.map(|rtt| rtt.as_secs_f64() * 1000.),
+            retransmits: result.retransmits,
+        })
+        .collect()
+}
+
+/// Prints the ranked results as a table, goodput in GiB/s.
+pub fn print_table(results: &[SweepResult]) {
+    println!(
+        "{:>4} {:>7} {:>8} {:>6} {:>6} {:>6} {:>14} {:>8} {:>11}",
+        "rank",
+        "nodelay",
+        "interval",
+        "resend",
+        "wnd",
+        "mtu",
+        "goodput_gib/s",
+        "rtt_ms",
+        "retransmits"
+    );
+    for (rank, result) in results.iter().enumerate() {
+        let rtt = match result.rtt {
+            Some(rtt) => format!("{:.1}", rtt.as_secs_f64() * 1000.),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:>4} {:>7} {:>8} {:>6} {:>6} {:>6} {:>14.6} {:>8} {:>11}",
+            rank + 1,
+            result.kcp.nodelay,
+            result.kcp.interval,
+            result.kcp.resend,
+            result.kcp.snd_wnd,
+            result.kcp.mtu,
+            result.goodput,
+            rtt,
+            result.retransmits
+        );
+    }
+}
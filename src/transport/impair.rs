@@ -0,0 +1,277 @@
+//! Emulated network impairments between a smoltcp stack and its device.
+//!
+//! Loss, corruption, packet size and rate limits come from smoltcp's own
+//! `FaultInjector`; `DelayLine` adds latency and reordering on top, which it
+//! lacks.
+
+use smoltcp::phy::{self, Device, DeviceCapabilities, FaultInjector, RxToken};
+use smoltcp::time::{Duration, Instant};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt;
+
+/// Interval the rate limit of `FaultInjector` refills its buckets at.
+const RATE_BUCKET_INTERVAL_MS: u64 = 10;
+
+/// Network conditions a stack emulates on the frames it exchanges. Each
+/// setting applies to both directions and zero disables it.
+#[derive(Debug, Clone)]
+pub struct ImpairmentConfig {
+    /// Percentage of frames dropped.
+    pub drop_pct: u8,
+    /// Percentage of frames with a random octet corrupted.
+    pub corrupt_pct: u8,
+    /// Frames per second let through.
+    pub max_rate: u64,
+    /// Frames larger than this are dropped.
+    pub max_packet_size: usize,
+    /// Latency added to every received frame.
+    pub delay_ms: u64,
+    /// Percentage of received frames held back by `reorder_delay_ms` more.
+    pub reorder_pct: u8,
+    pub reorder_delay_ms: u64,
+    /// Seed of the random choices, so that runs can be repeated.
+    pub seed: u32,
+}
+
+impl Default for ImpairmentConfig {
+    fn default() -> Self {
+        ImpairmentConfig {
+            drop_pct: 0,
+            corrupt_pct: 0,
+            max_rate: 0,
+            max_packet_size: 0,
+            delay_ms: 0,
+            reorder_pct: 0,
+            reorder_delay_ms: 10,
+            seed: 1,
+        }
+    }
+}
+
+impl fmt::Display for ImpairmentConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "drop={}% corrupt={}% rate={}pps max_packet={} delay={}ms reorder={}%/{}ms seed={}",
+            self.drop_pct,
+            self.corrupt_pct,
+            self.max_rate,
+            self.max_packet_size,
+            self.delay_ms,
+            self.reorder_pct,
+            self.reorder_delay_ms,
+            self.seed
+        )
+    }
+}
+
+impl ImpairmentConfig {
+    /// Whether any impairment is enabled.
+    pub fn is_active(&self) -> bool {
+        self.drop_pct > 0
+            || self.corrupt_pct > 0
+            || self.max_rate > 0
+            || self.max_packet_size > 0
+            || self.delay_ms > 0
+            || (self.reorder_pct > 0 && self.reorder_delay_ms > 0)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for &(name, pct) in &[
+            ("drop", self.drop_pct),
+            ("corrupt", self.corrupt_pct),
+            ("reorder", self.reorder_pct),
+        ] {
+            if pct > 100 {
+                return Err(format!(
+                    "{} percentage must be at most 100, got {}",
+                    name, pct
+                ));
+            }
+        }
+        if self.seed == 0 {
+            return Err("impairment seed must not be 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// Wraps `device` so that its frames go through the impairments.
+    pub fn wrap<D>(&self, device: D) -> DelayLine<FaultInjector<D>>
+    where
+        D: for<'d> Device<'d>,
+    {
+        let mut injector = FaultInjector::new(device, self.seed);
+        injector.set_drop_chance(self.drop_pct);
+        injector.set_corrupt_chance(self.corrupt_pct);
+        if self.max_packet_size > 0 {
+            injector.set_max_packet_size(self.max_packet_size);
+        }
+        if self.max_rate > 0 {
+            let per_bucket = (self.max_rate * RATE_BUCKET_INTERVAL_MS / 1000).max(1);
+            injector.set_bucket_interval(Duration::from_millis(RATE_BUCKET_INTERVAL_MS));
+            injector.set_max_tx_rate(per_bucket);
+            injector.set_max_rx_rate(per_bucket);
+        }
+        DelayLine::new(injector, self)
+    }
+}
+
+/// Device holding the frames received from `inner` back until they are due,
+/// some of them longer than others so that they arrive out of order.
+pub struct DelayLine<D> {
+    inner: D,
+    delay: std::time::Duration,
+    reorder_pct: u8,
+    reorder_delay: std::time::Duration,
+    rng: u32,
+    /// Frames by due time, then by arrival among those due together.
+    queue: BinaryHeap<Reverse<(std::time::Instant, u64, Vec<u8>)>>,
+    next_seq: u64,
+}
+
+impl<D> DelayLine<D> {
+    pub fn new(inner: D, config: &ImpairmentConfig) -> Self {
+        DelayLine {
+            inner,
+            delay: std::time::Duration::from_millis(config.delay_ms),
+            reorder_pct: config.reorder_pct,
+            reorder_delay: std::time::Duration::from_millis(config.reorder_delay_ms),
+            rng: config.seed.max(1),
+            queue: BinaryHeap::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// Xorshift32, as in smoltcp's fault injector.
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x
+    }
+
+    fn schedule(&mut self, frame: Vec<u8>, now: std::time::Instant) {
+        let mut due = now + self.delay;
+        if self.reorder_pct > 0 && self.next_random() % 100 < u32::from(self.reorder_pct) {
+            due += self.reorder_delay;
+        }
+        self.queue.push(Reverse((due, self.next_seq, frame)));
+        self.next_seq += 1;
+    }
+
+    fn has_due(&self, now: std::time::Instant) -> bool {
+        matches!(self.queue.peek(), Some(Reverse((due, _, _))) if *due <= now)
+    }
+}
+
+impl<'a, D> Device<'a> for DelayLine<D>
+where
+    D: for<'d> Device<'d>,
+{
+    type RxToken = DelayedRxToken;
+    type TxToken = <D as Device<'a>>::TxToken;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let now = std::time::Instant::now();
+        loop {
+            let frame = {
+                let (rx, _) = match self.inner.receive() {
+                    Some(tokens) => tokens,
+                    None => break,
+                };
+                // Frames the fault injector drops fail to be consumed.
+                rx.consume(Instant::now(), |frame| Ok(frame.to_vec()))
+            };
+            if let Ok(frame) = frame {
+                self.schedule(frame, now);
+            }
+        }
+
+        if !self.has_due(now) {
+            return None;
+        }
+        let tx = self.inner.transmit()?;
+        let Reverse((_, _, frame)) = self.queue.pop()?;
+        Some((DelayedRxToken(frame), tx))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        self.inner.transmit()
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+pub struct DelayedRxToken(Vec<u8>);
+
+impl phy::RxToken for DelayedRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        f(&mut self.0[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::pipe::{self, PIPE_MTU};
+    use smoltcp::phy::{RxToken, TxToken};
+
+    #[test]
+    fn delays_and_reorders_received_frames() {
+        let (mut a, b) = pipe::pair(PIPE_MTU);
+        let mut b = DelayLine::new(
+            b,
+            &ImpairmentConfig {
+                delay_ms: 20,
+                reorder_pct: 100,
+                reorder_delay_ms: 20,
+                ..ImpairmentConfig::default()
+            },
+        );
+        let now = Instant::from_millis(0);
+        a.transmit()
+            .unwrap()
+            .consume(now, 1, |frame| {
+                frame[0] = b'a';
+                Ok(())
+            })
+            .unwrap();
+        assert!(b.receive().is_none());
+
+        // Not held back, the second frame overtakes the first.
+        b.reorder_pct = 0;
+        a.transmit()
+            .unwrap()
+            .consume(now, 1, |frame| {
+                frame[0] = b'b';
+                Ok(())
+            })
+            .unwrap();
+        assert!(b.receive().is_none());
+
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        let (rx, _) = b.receive().unwrap();
+        rx.consume(now, |frame| {
+            assert_eq!(frame, b"b");
+            Ok(())
+        })
+        .unwrap();
+        assert!(b.receive().is_none());
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let (rx, _) = b.receive().unwrap();
+        rx.consume(now, |frame| {
+            assert_eq!(frame, b"a");
+            Ok(())
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,993 @@
+use crate::report::{KcpSample, KcpSampler, WireStats};
+use crate::transport::udp::MAX_DATAGRAM_SIZE;
+use crate::transport::{
+    bind_udp_socket, bucket_prefix, check_deadline, deadline, parse_bucket_prefix,
+    unspecified_addr, Listener, Transport, TransportOptions, TransportStats, STREAM_HELLO,
+};
+use ::kcp::Kcp;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::{Entry, HashMap};
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::net::{SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// First conversation id handed out by `allocate_convs`.
+pub const KCP_CONV: u32 = 0x11223344;
+/// Default MTU, small enough for a real network path.
+pub const KCP_MTU: usize = 1400;
+/// Size of the header KCP puts in front of every segment.
+pub const KCP_OVERHEAD: usize = 24;
+/// Command byte of a segment carrying data.
+const KCP_CMD_PUSH: u8 = 81;
+/// Command byte of a segment acknowledging data.
+const KCP_CMD_ACK: u8 = 82;
+/// `send_bucket` stops queueing once this many segments wait to be sent.
+const SND_QUEUE_LIMIT: usize = 1024;
+/// Largest slice handed to a single `Kcp::send`, which rejects buffers that
+/// would split into too many fragments.
+const MAX_SEGMENTS_PER_SEND: usize = 64;
+/// A listener expires a (peer, conv) pair that sent nothing for this long,
+/// which also ends its session if it is still open. Until then, a session
+/// whose transport was dropped keeps acknowledging its peer.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// `finish` gives up once the peer acknowledged nothing for this long.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the dispatcher wakes up to expire sessions and notice that its
+/// listener was dropped.
+const DISPATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether KCP carries a byte stream or keeps message boundaries, picked
+/// between `Kcp::new_stream` and `Kcp::new`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KcpMode {
+    Stream,
+    Message,
+}
+
+impl FromStr for KcpMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stream" => Ok(KcpMode::Stream),
+            "message" => Ok(KcpMode::Message),
+            _ => Err(format!("unknown kcp mode {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for KcpMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            KcpMode::Stream => "stream",
+            KcpMode::Message => "message",
+        })
+    }
+}
+
+/// Tuning of the KCP sessions of a test. The client picks it and sends it
+/// in the `PerfRequest` so that both ends run the same settings.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KcpConfig {
+    pub mode: KcpMode,
+    pub snd_wnd: u16,
+    pub rcv_wnd: u16,
+    pub nodelay: bool,
+    /// Internal update interval in milliseconds.
+    pub interval: u32,
+    /// Fast resend threshold passed to `set_nodelay`: a segment is resent once
+    /// this many later ones were acknowledged, 0 disables fast resend.
+    pub resend: u32,
+    /// Disables congestion control.
+    pub no_congestion: bool,
+    /// Replaces `resend` when set; KCP keeps a single fast resend threshold.
+    pub fast_resend: Option<u32>,
+    pub rx_minrto: u32,
+    pub mtu: usize,
+}
+
+impl Default for KcpConfig {
+    fn default() -> Self {
+        KcpConfig {
+            mode: KcpMode::Stream,
+            snd_wnd: 65535,
+            rcv_wnd: 65535,
+            nodelay: true,
+            interval: 10,
+            resend: 2,
+            no_congestion: true,
+            fast_resend: None,
+            rx_minrto: 10,
+            mtu: KCP_MTU,
+        }
+    }
+}
+
+impl KcpConfig {
+    /// Checks the settings a peer asked for before starting sessions with
+    /// them.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.mtu <= KCP_OVERHEAD || self.mtu > MAX_DATAGRAM_SIZE {
+            return Err(format!(
+                "kcp mtu must be between {} and {} bytes",
+                KCP_OVERHEAD + 1,
+                MAX_DATAGRAM_SIZE
+            ));
+        }
+        if self.snd_wnd == 0 || self.rcv_wnd == 0 {
+            return Err("kcp windows must not be empty".to_string());
+        }
+        if self.interval == 0 {
+            return Err("kcp update interval must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// Fast resend threshold the sessions run with.
+    pub fn fast_resend(&self) -> u32 {
+        self.fast_resend.unwrap_or(self.resend)
+    }
+}
+
+static NEXT_CONV: AtomicU32 = AtomicU32::new(KCP_CONV);
+
+/// Reserves `n` consecutive conversation ids and returns the first one, so
+/// that concurrent sessions of one server never share a conv.
+pub fn allocate_convs(n: usize) -> u32 {
+    NEXT_CONV.fetch_add(n as u32, Ordering::Relaxed)
+}
+
+/// Output sink handed to `kcp::Kcp`, writing every segment as one UDP datagram.
+///
+/// `peer` is `None` for connected sockets, otherwise segments go to `peer`
+/// with `send_to`. Every datagram written is counted in `wire`.
+pub struct KcpOutput {
+    pub socket: Arc<UdpSocket>,
+    pub peer: Option<SocketAddr>,
+    pub wire: Arc<Mutex<WireStats>>,
+    sent: Arc<SentState>,
+}
+
+/// What the `KcpOutput` of a session last sent, readable without going
+/// through `Kcp`.
+#[derive(Debug, Default)]
+struct SentState {
+    /// Sequence number of the next data segment not sent before.
+    next_sn: AtomicU32,
+    /// Receive window advertised in the latest segment.
+    wnd: AtomicU16,
+}
+
+impl Write for KcpOutput {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let nbytes = match self.peer {
+            Some(peer) => self.socket.send_to(data, peer)?,
+            None => self.socket.send(data)?,
+        };
+
+        let mut wire = self.wire.lock().unwrap();
+        wire.send_ndatagrams += 1;
+        wire.send_nbytes += nbytes;
+        let sent = &self.sent;
+        for_each_segment(&data[..nbytes], |segment| {
+            sent.wnd.store(segment.wnd, Ordering::Relaxed);
+            if segment.cmd != KCP_CMD_PUSH {
+                return;
+            }
+            wire.send_payload_nbytes += segment.len;
+            let next_sn = sent.next_sn.load(Ordering::Relaxed);
+            if (segment.sn.wrapping_sub(next_sn) as i32) < 0 {
+                wire.send_retransmits += 1;
+            } else {
+                sent.next_sn
+                    .store(segment.sn.wrapping_add(1), Ordering::Relaxed);
+            }
+        });
+        Ok(nbytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Current time in milliseconds, as expected by `Kcp::update`.
+pub fn now_millis() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u32
+}
+
+/// Header fields of a KCP segment used for accounting.
+struct SegmentHeader {
+    cmd: u8,
+    wnd: u16,
+    ts: u32,
+    sn: u32,
+    una: u32,
+    /// Payload bytes actually present, which is less than the header says
+    /// for a truncated datagram.
+    len: usize,
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(value)
+}
+
+/// Calls `f` for every segment of a datagram, which KCP may pack with several
+/// segments of any kind.
+fn for_each_segment<F: FnMut(&SegmentHeader)>(mut datagram: &[u8], mut f: F) {
+    while datagram.len() >= KCP_OVERHEAD {
+        let end = std::cmp::min(
+            KCP_OVERHEAD + read_u32(&datagram[20..]) as usize,
+            datagram.len(),
+        );
+        f(&SegmentHeader {
+            cmd: datagram[4],
+            wnd: read_u16(&datagram[6..]),
+            ts: read_u32(&datagram[8..]),
+            sn: read_u32(&datagram[12..]),
+            una: read_u32(&datagram[16..]),
+            len: end - KCP_OVERHEAD,
+        });
+        datagram = &datagram[end..];
+    }
+}
+
+/// What a session learns about its peer from the headers of incoming
+/// segments. KCP keeps its own round trip estimate private, so the smoothed
+/// RTT and RTO are recomputed here the same way from the ack samples.
+struct PeerTracker {
+    srtt: u32,
+    rttval: u32,
+    rto: u32,
+    interval: u32,
+    rx_minrto: u32,
+    /// Receive window last advertised by the peer.
+    remote_wnd: u16,
+    /// Newest sequence number the peer has not received in order yet.
+    una: u32,
+}
+
+impl PeerTracker {
+    /// Initial RTO of a KCP session, in milliseconds.
+    const INITIAL_RTO: u32 = 200;
+    const MAX_RTO: u32 = 60000;
+
+    fn new(config: &KcpConfig) -> Self {
+        PeerTracker {
+            srtt: 0,
+            rttval: 0,
+            rto: Self::INITIAL_RTO,
+            interval: config.interval,
+            rx_minrto: config.rx_minrto,
+            remote_wnd: 0,
+            una: 0,
+        }
+    }
+
+    fn add_rtt_sample(&mut self, rtt: u32) {
+        if self.srtt == 0 {
+            self.srtt = rtt;
+            self.rttval = rtt / 2;
+        } else {
+            self.rttval = (3 * self.rttval + rtt.abs_diff(self.srtt)) / 4;
+            self.srtt = std::cmp::max((7 * self.srtt + rtt) / 8, 1);
+        }
+        let rto = self.srtt + std::cmp::max(self.interval, 4 * self.rttval);
+        self.rto = rto.max(self.rx_minrto).min(Self::MAX_RTO);
+    }
+}
+
+/// Counts a datagram that arrived for a session. Acks echo the timestamp of
+/// the segment they acknowledge, which gives a round trip sample each.
+fn count_received(wire: &Mutex<WireStats>, peer: &mut PeerTracker, datagram: &[u8]) {
+    let now = now_millis();
+    let mut wire = wire.lock().unwrap();
+    wire.recv_ndatagrams += 1;
+    wire.recv_nbytes += datagram.len();
+    for_each_segment(datagram, |segment| {
+        peer.remote_wnd = segment.wnd;
+        if (segment.una.wrapping_sub(peer.una) as i32) > 0 {
+            peer.una = segment.una;
+        }
+        match segment.cmd {
+            KCP_CMD_PUSH => wire.recv_payload_nbytes += segment.len,
+            KCP_CMD_ACK => {
+                let rtt = now.wrapping_sub(segment.ts);
+                wire.rtt_sum_ms += u64::from(rtt);
+                wire.rtt_nsamples += 1;
+                peer.add_rtt_sample(rtt);
+            }
+            _ => {}
+        }
+    });
+}
+
+fn kcp_error(err: ::kcp::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("kcp error: {:?}", err))
+}
+
+/// Where the segments of a session come from.
+enum KcpInput {
+    /// A connected socket that only carries this session.
+    Socket(Arc<UdpSocket>),
+    /// Segments routed to this session by a `KcpTransportListener`.
+    Channel(Receiver<Vec<u8>>),
+}
+
+/// KCP state shared by the halves of a split `KcpTransport`.
+struct KcpSession {
+    kcp: Kcp<KcpOutput>,
+    input: KcpInput,
+    /// Shared with the `KcpOutput` inside `kcp`, which counts what is sent.
+    wire: Arc<Mutex<WireStats>>,
+    sent: Arc<SentState>,
+    peer: PeerTracker,
+    /// Deepest the send queue got.
+    max_wait_snd: usize,
+    datagram: Vec<u8>,
+    /// Bytes handed out by `Kcp::recv` but not consumed by the caller yet.
+    pending: Vec<u8>,
+    pending_offset: usize,
+}
+
+impl KcpSession {
+    /// Runs KCP timers and feeds every datagram waiting on the socket into
+    /// the session. Returns whether any datagram arrived.
+    fn pump(&mut self) -> io::Result<bool> {
+        self.kcp.update(now_millis()).map_err(kcp_error)?;
+
+        let mut received = false;
+        match &self.input {
+            KcpInput::Socket(socket) => loop {
+                match socket.recv_from(&mut self.datagram[..]) {
+                    Ok((nbytes, _)) => {
+                        count_received(&self.wire, &mut self.peer, &self.datagram[..nbytes]);
+                        self.kcp
+                            .input(&self.datagram[..nbytes])
+                            .map_err(kcp_error)?;
+                        received = true;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            },
+            KcpInput::Channel(segments) => loop {
+                match segments.try_recv() {
+                    Ok(segment) => {
+                        count_received(&self.wire, &mut self.peer, &segment[..]);
+                        self.kcp.input(&segment[..]).map_err(kcp_error)?;
+                        received = true;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "kcp session expired or its listener was closed",
+                        ));
+                    }
+                }
+            },
+        }
+        if received {
+            self.kcp.update(now_millis()).map_err(kcp_error)?;
+        }
+
+        Ok(received)
+    }
+
+    fn sample(&self) -> KcpSample {
+        let next_sn = self.sent.next_sn.load(Ordering::Relaxed);
+        KcpSample {
+            srtt_ms: self.peer.srtt,
+            rto_ms: self.peer.rto,
+            wait_snd: self.kcp.wait_snd(),
+            max_wait_snd: self.max_wait_snd,
+            retransmits: self.wire.lock().unwrap().send_retransmits,
+            inflight: std::cmp::max(next_sn.wrapping_sub(self.peer.una) as i32, 0) as u32,
+            rcv_wnd: self.sent.wnd.load(Ordering::Relaxed),
+            remote_wnd: self.peer.remote_wnd,
+        }
+    }
+
+    /// Queues as much of `buf` as the send queue allows and returns how many
+    /// bytes were taken, possibly none.
+    fn try_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.max_wait_snd = std::cmp::max(self.max_wait_snd, self.kcp.wait_snd());
+        if self.kcp.wait_snd() >= SND_QUEUE_LIMIT {
+            self.pump()?;
+            return Ok(0);
+        }
+
+        let max_send = self.kcp.mss() * MAX_SEGMENTS_PER_SEND;
+        let n = std::cmp::min(buf.len(), max_send);
+        let n = self.kcp.send(&buf[..n]).map_err(kcp_error)?;
+        self.pump()?;
+        Ok(n)
+    }
+
+    /// Copies received bytes into `buf` and returns how many, possibly none.
+    fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending_offset == self.pending.len() {
+            match self.kcp.peeksize() {
+                Ok(size) => {
+                    self.pending.resize(size, 0);
+                    let n = self.kcp.recv(&mut self.pending[..]).map_err(kcp_error)?;
+                    self.pending.truncate(n);
+                    self.pending_offset = 0;
+                }
+                Err(::kcp::Error::RecvQueueEmpty) | Err(::kcp::Error::ExpectingFragment) => {
+                    self.pump()?;
+                    return Ok(0);
+                }
+                Err(err) => return Err(kcp_error(err)),
+            }
+        }
+
+        let available = &self.pending[self.pending_offset..];
+        let n = std::cmp::min(available.len(), buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pending_offset += n;
+        Ok(n)
+    }
+}
+
+/// KCP session in stream mode over a UDP socket, carrying length-prefixed
+/// buckets like `TcpTransport`.
+pub struct KcpTransport {
+    session: Arc<Mutex<KcpSession>>,
+    /// Longest reads and writes wait for the peer to make progress.
+    peer_timeout: Option<Duration>,
+    stats: TransportStats,
+}
+
+impl KcpTransport {
+    /// Starts session `conv` tuned by `config`, sending on `socket` and
+    /// receiving from `input`; `peer` must be set unless the socket is
+    /// connected.
+    fn new(
+        conv: u32,
+        config: &KcpConfig,
+        socket: Arc<UdpSocket>,
+        peer: Option<SocketAddr>,
+        input: KcpInput,
+    ) -> io::Result<Self> {
+        let wire = Arc::new(Mutex::new(WireStats::default()));
+        let sent = Arc::new(SentState::default());
+        let output = KcpOutput {
+            socket,
+            peer,
+            wire: wire.clone(),
+            sent: sent.clone(),
+        };
+        let mut kcp = match config.mode {
+            KcpMode::Stream => Kcp::new_stream(conv, output),
+            KcpMode::Message => Kcp::new(conv, output),
+        };
+        kcp.set_wndsize(config.snd_wnd, config.rcv_wnd);
+        kcp.set_nodelay(
+            config.nodelay,
+            config.interval as i32,
+            config.fast_resend() as i32,
+            config.no_congestion,
+        );
+        kcp.set_rx_minrto(config.rx_minrto);
+        kcp.set_mtu(config.mtu).map_err(kcp_error)?;
+
+        let session = KcpSession {
+            kcp,
+            input,
+            wire,
+            sent,
+            peer: PeerTracker::new(config),
+            max_wait_snd: 0,
+            datagram: vec![0; MAX_DATAGRAM_SIZE],
+            pending: Vec::new(),
+            pending_offset: 0,
+        };
+        Ok(KcpTransport {
+            session: Arc::new(Mutex::new(session)),
+            peer_timeout: None,
+            stats: TransportStats::default(),
+        })
+    }
+
+    fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        let mut stalled_until = deadline(self.peer_timeout);
+        while !buf.is_empty() {
+            let n = self.session.lock().unwrap().try_write(buf)?;
+            if n == 0 {
+                check_deadline(stalled_until, "the kcp peer to acknowledge data")?;
+                std::thread::yield_now();
+            } else {
+                stalled_until = deadline(self.peer_timeout);
+            }
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    fn read_exact(&mut self, mut buf: &mut [u8]) -> io::Result<()> {
+        let mut stalled_until = deadline(self.peer_timeout);
+        while !buf.is_empty() {
+            let n = self.session.lock().unwrap().try_read(buf)?;
+            if n == 0 {
+                check_deadline(stalled_until, "data from the kcp peer")?;
+                std::thread::yield_now();
+            } else {
+                stalled_until = deadline(self.peer_timeout);
+            }
+            let tmp = buf;
+            buf = &mut tmp[n..];
+        }
+        Ok(())
+    }
+}
+
+impl Transport for KcpTransport {
+    fn connect(addr: &SocketAddr, options: &TransportOptions) -> io::Result<Self> {
+        let socket = bind_udp_socket(&unspecified_addr(addr))?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+
+        let socket = Arc::new(socket);
+        let mut transport = KcpTransport::new(
+            options.kcp_conv,
+            &options.kcp,
+            socket.clone(),
+            None,
+            KcpInput::Socket(socket),
+        )?;
+        transport.peer_timeout = options.peer_timeout;
+        transport.write_all(&STREAM_HELLO[..])?;
+        Ok(transport)
+    }
+
+    fn send_bucket(&mut self, bucket: &[u8]) -> io::Result<()> {
+        self.write_all(&bucket_prefix(bucket.len())[..])?;
+        self.write_all(bucket)?;
+
+        self.stats.send_nbytes += bucket.len();
+        self.stats.send_nbuckets += 1;
+        Ok(())
+    }
+
+    fn recv_bucket(&mut self, bucket: &mut [u8]) -> io::Result<Option<usize>> {
+        let mut prefix = [0; 8];
+        self.read_exact(&mut prefix[..])?;
+        let nbytes = match parse_bucket_prefix(prefix, bucket.len())? {
+            Some(nbytes) => nbytes,
+            None => return Ok(None),
+        };
+        self.read_exact(&mut bucket[..nbytes])?;
+
+        self.stats.recv_nbytes += nbytes;
+        self.stats.recv_nbuckets += 1;
+        Ok(Some(nbytes))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_all(&bucket_prefix(0)[..])?;
+        let mut wait_snd = usize::MAX;
+        let mut last_progress = Instant::now();
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                if session.kcp.wait_snd() == 0 {
+                    break;
+                }
+                if session.kcp.wait_snd() < wait_snd {
+                    wait_snd = session.kcp.wait_snd();
+                    last_progress = Instant::now();
+                } else if last_progress.elapsed() >= FINISH_TIMEOUT {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "kcp peer acknowledged nothing for {:?}, {} segments unsent",
+                            FINISH_TIMEOUT, wait_snd
+                        ),
+                    ));
+                }
+                session.pump()?;
+            }
+            std::thread::yield_now();
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.session.lock().unwrap().kcp.flush().map_err(kcp_error)
+    }
+
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn Transport>, Box<dyn Transport>)> {
+        let other = KcpTransport {
+            session: self.session.clone(),
+            peer_timeout: self.peer_timeout,
+            stats: TransportStats::default(),
+        };
+        Ok((self, Box::new(other)))
+    }
+
+    fn stats(&self) -> TransportStats {
+        let wire = *self.session.lock().unwrap().wire.lock().unwrap();
+        TransportStats {
+            wire: Some(wire),
+            ..self.stats
+        }
+    }
+
+    fn retransmits(&self) -> Option<u64> {
+        let session = self.session.lock().unwrap();
+        let retransmits = session.wire.lock().unwrap().send_retransmits;
+        Some(retransmits as u64)
+    }
+
+    fn kcp_sample(&self) -> Option<KcpSample> {
+        Some(self.session.lock().unwrap().sample())
+    }
+
+    fn kcp_sampler(&self) -> Option<KcpSampler> {
+        // Weak, so that the sampler does not keep the session from lingering.
+        let session = Arc::downgrade(&self.session);
+        Some(Box::new(move || {
+            session
+                .upgrade()
+                .map(|session| session.lock().unwrap().sample())
+        }))
+    }
+}
+
+/// A session found by the dispatcher, waiting to be accepted.
+struct NewSession {
+    peer: SocketAddr,
+    conv: u32,
+    transport: KcpTransport,
+}
+
+/// Where the dispatcher sends the segments of one (peer, conv) pair.
+enum Route {
+    Open {
+        segments: Sender<Vec<u8>>,
+        /// Also held by the transport until it is dropped; the dispatcher
+        /// then pumps the session itself, so that the peer still gets its
+        /// acks and retransmits until the pair expires.
+        session: Arc<Mutex<KcpSession>>,
+        last_seen: Instant,
+    },
+    /// The pair went idle; late segments are dropped instead of starting a
+    /// new session.
+    Expired,
+}
+
+impl Route {
+    /// Pumps the session if its transport is gone.
+    fn linger(session: &Arc<Mutex<KcpSession>>) {
+        if Arc::strong_count(session) == 1 {
+            let _ = session.lock().unwrap().pump();
+        }
+    }
+}
+
+/// Conversation ids a listener accepts sessions for.
+#[derive(Debug, Clone, Copy)]
+struct ConvRange {
+    first: u32,
+    len: usize,
+}
+
+impl ConvRange {
+    fn contains(&self, conv: u32) -> bool {
+        (conv.wrapping_sub(self.first) as usize) < self.len
+    }
+}
+
+/// Serves KCP sessions from any number of peers on one UDP socket.
+///
+/// A dispatcher thread reads every datagram and routes it by peer address and
+/// conversation id; the first segment of an unknown pair starts a session
+/// that `accept` hands out, provided the conv is one of those allocated to
+/// the listener.
+pub struct KcpTransportListener {
+    socket: Arc<UdpSocket>,
+    accept_timeout: Option<Duration>,
+    peer_timeout: Option<Duration>,
+    sessions: Receiver<NewSession>,
+    closed: Arc<AtomicBool>,
+    dispatcher: Option<JoinHandle<()>>,
+}
+
+impl KcpTransportListener {
+    fn bind_with_idle_timeout(
+        addr: &SocketAddr,
+        options: &TransportOptions,
+        idle_timeout: Duration,
+    ) -> io::Result<Self> {
+        let socket = Arc::new(bind_udp_socket(addr)?);
+        socket.set_read_timeout(Some(DISPATCH_POLL_INTERVAL))?;
+
+        let (sender, sessions) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let dispatcher = {
+            let socket = socket.clone();
+            let config = options.kcp;
+            let convs = ConvRange {
+                first: options.kcp_conv,
+                len: options.kcp_nconvs,
+            };
+            let closed = closed.clone();
+            std::thread::spawn(move || {
+                Self::dispatch(&socket, &config, convs, idle_timeout, sender, &closed)
+            })
+        };
+        Ok(KcpTransportListener {
+            socket,
+            accept_timeout: options.accept_timeout,
+            peer_timeout: options.peer_timeout,
+            sessions,
+            closed,
+            dispatcher: Some(dispatcher),
+        })
+    }
+
+    fn dispatch(
+        socket: &Arc<UdpSocket>,
+        config: &KcpConfig,
+        convs: ConvRange,
+        idle_timeout: Duration,
+        sessions: Sender<NewSession>,
+        closed: &AtomicBool,
+    ) {
+        let mut routes: HashMap<(SocketAddr, u32), Route> = HashMap::new();
+        let mut datagram = vec![0; MAX_DATAGRAM_SIZE];
+        let mut last_expiry = Instant::now();
+        while !closed.load(Ordering::Relaxed) {
+            if last_expiry.elapsed() >= DISPATCH_POLL_INTERVAL {
+                for route in routes.values_mut() {
+                    if let Route::Open {
+                        session, last_seen, ..
+                    } = route
+                    {
+                        if last_seen.elapsed() >= idle_timeout {
+                            *route = Route::Expired;
+                        } else {
+                            Route::linger(session);
+                        }
+                    }
+                }
+                last_expiry = Instant::now();
+            }
+
+            let (nbytes, peer) = match socket.recv_from(&mut datagram[..]) {
+                Ok(received) => received,
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue;
+                }
+                Err(err) => {
+                    tracing::warn!("kcp dispatcher stopped: {}", err);
+                    return;
+                }
+            };
+            if nbytes < KCP_OVERHEAD {
+                continue;
+            }
+
+            let conv = ::kcp::get_conv(&datagram[..nbytes]);
+            let route = match routes.entry((peer, conv)) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(_) if !convs.contains(conv) => {
+                    tracing::debug!("dropped segment of unknown conv {:#x} from {}", conv, peer);
+                    continue;
+                }
+                Entry::Vacant(entry) => {
+                    let (sender, receiver) = mpsc::channel();
+                    let transport = match KcpTransport::new(
+                        conv,
+                        config,
+                        socket.clone(),
+                        Some(peer),
+                        KcpInput::Channel(receiver),
+                    ) {
+                        Ok(transport) => transport,
+                        Err(err) => {
+                            tracing::warn!("kcp session from {} failed: {}", peer, err);
+                            continue;
+                        }
+                    };
+                    let session = transport.session.clone();
+                    // Not accepted if the listener is being dropped; the
+                    // session then lingers like any other.
+                    let _ = sessions.send(NewSession {
+                        peer,
+                        conv,
+                        transport,
+                    });
+                    entry.insert(Route::Open {
+                        segments: sender,
+                        session,
+                        last_seen: Instant::now(),
+                    })
+                }
+            };
+            if let Route::Open {
+                segments,
+                session,
+                last_seen,
+            } = route
+            {
+                *last_seen = Instant::now();
+                // The session owns the receiver, so this cannot fail.
+                let _ = segments.send(datagram[..nbytes].to_vec());
+                Route::linger(session);
+            }
+        }
+    }
+}
+
+impl Listener for KcpTransportListener {
+    type Transport = KcpTransport;
+
+    /// Accepts the sessions of the `options.kcp_nconvs` conversation ids
+    /// from `options.kcp_conv` on.
+    fn bind(addr: &SocketAddr, options: &TransportOptions) -> io::Result<Self> {
+        Self::bind_with_idle_timeout(addr, options, SESSION_IDLE_TIMEOUT)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn accept(&mut self) -> io::Result<KcpTransport> {
+        let session = match self.accept_timeout {
+            Some(timeout) => self.sessions.recv_timeout(timeout),
+            None => self
+                .sessions
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let session = session.map_err(|err| match err {
+            RecvTimeoutError::Timeout => io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out waiting for a kcp session",
+            ),
+            RecvTimeoutError::Disconnected => {
+                io::Error::new(io::ErrorKind::Other, "kcp dispatcher stopped")
+            }
+        })?;
+
+        let mut transport = session.transport;
+        transport.peer_timeout = self.peer_timeout;
+        let mut hello = [0; 8];
+        transport.read_exact(&mut hello[..])?;
+        if hello != STREAM_HELLO {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unexpected stream hello {:?} from {} conv {:#x}",
+                    hello, session.peer, session.conv
+                ),
+            ));
+        }
+        Ok(transport)
+    }
+}
+
+impl Drop for KcpTransportListener {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Some(dispatcher) = self.dispatcher.take() {
+            let _ = dispatcher.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(cmd: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0; KCP_OVERHEAD];
+        segment[..4].copy_from_slice(&KCP_CONV.to_le_bytes()[..]);
+        segment[4] = cmd;
+        segment[20..24].copy_from_slice(&(payload.len() as u32).to_le_bytes()[..]);
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    #[test]
+    fn parses_packed_segments() {
+        let mut datagram = segment(KCP_CMD_PUSH, &[0; 100]);
+        datagram.extend(segment(KCP_CMD_ACK, &[]));
+        datagram.extend(segment(KCP_CMD_PUSH, &[0; 7]));
+        // A truncated segment only counts what is there.
+        datagram.extend(&segment(KCP_CMD_PUSH, &[0; 50])[..KCP_OVERHEAD + 10]);
+
+        let mut segments = Vec::new();
+        for_each_segment(&datagram[..], |segment| {
+            segments.push((segment.cmd, segment.len))
+        });
+        assert_eq!(
+            segments,
+            vec![
+                (KCP_CMD_PUSH, 100),
+                (KCP_CMD_ACK, 0),
+                (KCP_CMD_PUSH, 7),
+                (KCP_CMD_PUSH, 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn fast_resend_replaces_resend_only_when_set() {
+        let config = KcpConfig {
+            resend: 2,
+            ..KcpConfig::default()
+        };
+        assert_eq!(config.fast_resend(), 2);
+        let config = KcpConfig {
+            fast_resend: Some(0),
+            ..config
+        };
+        assert_eq!(config.fast_resend(), 0);
+    }
+
+    #[test]
+    fn ignores_expired_and_unallocated_convs() {
+        let conv = allocate_convs(1);
+        let options = TransportOptions {
+            kcp_conv: conv,
+            ..TransportOptions::default()
+        };
+        let mut listener = KcpTransportListener::bind_with_idle_timeout(
+            &"127.0.0.1:0".parse().unwrap(),
+            &options,
+            Duration::from_millis(300),
+        )
+        .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut stranger = KcpTransport::connect(
+            &addr,
+            &TransportOptions {
+                kcp_conv: conv.wrapping_add(1),
+                ..TransportOptions::default()
+            },
+        )
+        .unwrap();
+        stranger.close().unwrap();
+
+        let mut client = KcpTransport::connect(&addr, &options).unwrap();
+        let mut server = listener.accept().unwrap();
+        let mut bucket = [0; 16];
+        client.send_bucket(&[1; 16]).unwrap();
+        assert_eq!(server.recv_bucket(&mut bucket).unwrap(), Some(16));
+        // The session lingers to acknowledge the end marker.
+        drop(server);
+        client.finish().unwrap();
+
+        std::thread::sleep(Duration::from_millis(600));
+        client.send_bucket(&[1; 16]).unwrap();
+        client.close().unwrap();
+        assert!(listener
+            .sessions
+            .recv_timeout(Duration::from_millis(300))
+            .is_err());
+    }
+}
@@ -0,0 +1,261 @@
+pub mod impair;
+pub mod kcp;
+pub mod pipe;
+pub mod smoltcp;
+pub mod stack;
+pub mod tcp;
+pub mod udp;
+
+use crate::report::{KcpSample, KcpSampler, UdpStats, WireStats};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const SOCKET_BUFFER_SIZE: usize = 4194304;
+
+/// Protocol carrying the data streams of a test.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Tcp,
+    Kcp,
+    SmoltcpUdp,
+    Udp,
+    SmoltcpTcp,
+}
+
+impl TransportKind {
+    /// Bucket size used by the client when none is given on the command line.
+    pub fn default_bucket_size(&self) -> usize {
+        match self {
+            TransportKind::Tcp | TransportKind::SmoltcpTcp => 1024 * 1024,
+            TransportKind::Kcp | TransportKind::SmoltcpUdp => 32768,
+            TransportKind::Udp => udp::DEFAULT_PAYLOAD_SIZE,
+        }
+    }
+
+    /// Whether one data listener of this kind can serve more than one stream.
+    pub fn supports_multiple_streams(&self) -> bool {
+        match self {
+            TransportKind::Tcp | TransportKind::Kcp | TransportKind::SmoltcpTcp => true,
+            TransportKind::SmoltcpUdp | TransportKind::Udp => false,
+        }
+    }
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(TransportKind::Tcp),
+            "kcp" => Ok(TransportKind::Kcp),
+            "smoltcp-udp" => Ok(TransportKind::SmoltcpUdp),
+            "udp" => Ok(TransportKind::Udp),
+            "smoltcp-tcp" => Ok(TransportKind::SmoltcpTcp),
+            _ => Err(format!(
+                "unknown transport {:?}, expected tcp, kcp, smoltcp-udp, udp or smoltcp-tcp",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TransportKind::Tcp => "tcp",
+            TransportKind::Kcp => "kcp",
+            TransportKind::SmoltcpUdp => "smoltcp-udp",
+            TransportKind::Udp => "udp",
+            TransportKind::SmoltcpTcp => "smoltcp-tcp",
+        })
+    }
+}
+
+/// Settings shared by every transport when a stream is set up.
+#[derive(Debug, Clone)]
+pub struct TransportOptions {
+    pub bucket_size: usize,
+    /// Conversation id of a KCP stream, assigned by the server.
+    pub kcp_conv: u32,
+    /// Number of conversation ids from `kcp_conv` on a KCP listener accepts.
+    pub kcp_nconvs: usize,
+    pub kcp: kcp::KcpConfig,
+    /// Kernel pacing rate of TCP sockets in bytes per second.
+    pub pacing_rate: Option<u64>,
+    pub smoltcp_tcp: smoltcp::SmoltcpTcpConfig,
+    /// User-space stack of the smoltcp transports.
+    pub stack: Option<Arc<stack::Stack>>,
+    /// Longest a listener waits in `accept` before failing with `TimedOut`,
+    /// `None` waits forever.
+    pub accept_timeout: Option<Duration>,
+    /// Longest a stream waits for its peer to take or deliver data before
+    /// failing, `None` waits forever.
+    pub peer_timeout: Option<Duration>,
+}
+
+impl Default for TransportOptions {
+    fn default() -> Self {
+        TransportOptions {
+            bucket_size: 1024 * 1024,
+            kcp_conv: kcp::KCP_CONV,
+            kcp_nconvs: 1,
+            kcp: kcp::KcpConfig::default(),
+            pacing_rate: None,
+            smoltcp_tcp: smoltcp::SmoltcpTcpConfig::default(),
+            stack: None,
+            accept_timeout: None,
+            peer_timeout: None,
+        }
+    }
+}
+
+/// Counters kept by a transport over the lifetime of one stream.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TransportStats {
+    pub send_nbytes: usize,
+    pub recv_nbytes: usize,
+    pub send_nbuckets: usize,
+    pub recv_nbuckets: usize,
+    /// Wire level counters, for transports that keep them.
+    pub wire: Option<WireStats>,
+    /// Receiver side datagram accounting of kernel UDP.
+    pub udp: Option<UdpStats>,
+}
+
+/// One data stream of a test, independent of the protocol carrying it.
+pub trait Transport: Send {
+    fn connect(addr: &SocketAddr, options: &TransportOptions) -> io::Result<Self>
+    where
+        Self: Sized;
+
+    /// Sends one bucket, blocking until the transport has accepted all of it.
+    fn send_bucket(&mut self, bucket: &[u8]) -> io::Result<()>;
+
+    /// Receives one bucket into `bucket` and returns its size, or `None` once
+    /// the peer has signalled the end of the test.
+    fn recv_bucket(&mut self, bucket: &mut [u8]) -> io::Result<Option<usize>>;
+
+    /// Signals the end of the test to the peer and waits for queued data to
+    /// leave.
+    fn finish(&mut self) -> io::Result<()>;
+
+    fn close(&mut self) -> io::Result<()>;
+
+    /// Splits the stream into a sending half and a receiving half that can be
+    /// driven from different threads at the same time.
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn Transport>, Box<dyn Transport>)>;
+
+    fn stats(&self) -> TransportStats;
+
+    /// Total number of retransmitted segments, for transports that can tell.
+    fn retransmits(&self) -> Option<u64> {
+        None
+    }
+
+    /// Current internal state of a KCP stream.
+    fn kcp_sample(&self) -> Option<KcpSample> {
+        None
+    }
+
+    /// Takes samples like `kcp_sample` from another thread, for as long as
+    /// the stream lives.
+    fn kcp_sampler(&self) -> Option<KcpSampler> {
+        None
+    }
+}
+
+/// Server side of a transport, producing one `Transport` per incoming stream.
+pub trait Listener: Send {
+    type Transport: Transport;
+
+    fn bind(addr: &SocketAddr, options: &TransportOptions) -> io::Result<Self>
+    where
+        Self: Sized;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Waits for the next stream, for at most the `accept_timeout` of the
+    /// options the listener was bound with.
+    fn accept(&mut self) -> io::Result<Self::Transport>;
+}
+
+/// Sent by the client when it opens a stream over a datagram transport, so the
+/// server learns the client's address whichever way the data flows.
+pub const STREAM_HELLO: [u8; 8] = *b"RIPERF\x00\x01";
+/// Unacknowledged markers on lossy transports are sent this many times.
+pub(crate) const MARKER_REPEAT: usize = 3;
+
+/// Length prefix written in front of every bucket on stream transports. A zero
+/// prefix marks the end of the test.
+pub(crate) fn bucket_prefix(nbytes: usize) -> [u8; 8] {
+    (nbytes as u64).to_be_bytes()
+}
+
+pub(crate) fn parse_bucket_prefix(prefix: [u8; 8], capacity: usize) -> io::Result<Option<usize>> {
+    let nbytes = u64::from_be_bytes(prefix) as usize;
+    if nbytes == 0 {
+        return Ok(None);
+    }
+    if nbytes > capacity {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "bucket of {} bytes exceeds buffer of {} bytes",
+                nbytes, capacity
+            ),
+        ));
+    }
+    Ok(Some(nbytes))
+}
+
+/// When a wait of at most `timeout` from now has to end.
+pub(crate) fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.map(|timeout| Instant::now() + timeout)
+}
+
+/// Fails with `TimedOut` once `deadline` has passed while waiting for `what`.
+pub(crate) fn check_deadline(deadline: Option<Instant>, what: &str) -> io::Result<()> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("timed out waiting for {}", what),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Wildcard address of the same family as `peer`, with port 0.
+pub(crate) fn unspecified_addr(peer: &SocketAddr) -> SocketAddr {
+    match peer {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    }
+}
+
+/// Creates a UDP socket bound to `addr` with enlarged kernel buffers.
+pub fn bind_udp_socket(addr: &SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(
+        match addr {
+            SocketAddr::V4(_) => Domain::IPV4,
+            SocketAddr::V6(_) => Domain::IPV6,
+        },
+        Type::DGRAM,
+        None,
+    )?;
+    socket.bind(&(*addr).into())?;
+    socket.set_send_buffer_size(SOCKET_BUFFER_SIZE)?;
+    socket.set_recv_buffer_size(SOCKET_BUFFER_SIZE)?;
+    tracing::debug!(
+        "send_buffer_size={}, recv_buffer_size={}",
+        socket.send_buffer_size()?,
+        socket.recv_buffer_size()?
+    );
+
+    Ok(socket.into())
+}
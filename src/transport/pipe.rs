@@ -0,0 +1,148 @@
+//! In-memory Ethernet link between two smoltcp stacks of one process, for
+//! testing and benchmarking the user-space stack without TAP privileges.
+
+use crate::transport::stack::Waker;
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Frame size of the link, that of an Ethernet without jumbo frames.
+pub const PIPE_MTU: usize = 1514;
+/// Frames a direction of the link holds before senders have to wait.
+const PIPE_QUEUE_LEN: usize = 1024;
+
+/// Frames in flight towards one end, and the waker of that end's stack.
+#[derive(Default)]
+struct Queue {
+    frames: Mutex<VecDeque<Vec<u8>>>,
+    waker: Arc<Waker>,
+}
+
+impl Queue {
+    fn push(&self, frame: Vec<u8>) {
+        self.frames.lock().unwrap().push_back(frame);
+        self.waker.wake();
+    }
+
+    fn is_full(&self) -> bool {
+        self.frames.lock().unwrap().len() >= PIPE_QUEUE_LEN
+    }
+}
+
+/// One end of an in-memory link created by `pair`.
+pub struct PipeDevice {
+    rx: Arc<Queue>,
+    tx: Arc<Queue>,
+    mtu: usize,
+}
+
+/// Creates the two ends of a link carrying frames of up to `mtu` bytes.
+pub fn pair(mtu: usize) -> (PipeDevice, PipeDevice) {
+    let a = Arc::new(Queue::default());
+    let b = Arc::new(Queue::default());
+    (
+        PipeDevice {
+            rx: a.clone(),
+            tx: b.clone(),
+            mtu,
+        },
+        PipeDevice { rx: b, tx: a, mtu },
+    )
+}
+
+impl PipeDevice {
+    /// Woken whenever a frame arrives at this end.
+    pub fn waker(&self) -> Arc<Waker> {
+        self.rx.waker.clone()
+    }
+}
+
+impl<'a> Device<'a> for PipeDevice {
+    type RxToken = PipeRxToken;
+    type TxToken = PipeTxToken<'a>;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let frame = self.rx.frames.lock().unwrap().pop_front()?;
+        Some((PipeRxToken(frame), PipeTxToken(&self.tx)))
+    }
+
+    /// Holds frames back while the far end is behind, like a full NIC ring.
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        if self.tx.is_full() {
+            return None;
+        }
+        Some(PipeTxToken(&self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+pub struct PipeRxToken(Vec<u8>);
+
+impl phy::RxToken for PipeRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        f(&mut self.0[..])
+    }
+}
+
+pub struct PipeTxToken<'a>(&'a Queue);
+
+impl<'a> phy::TxToken for PipeTxToken<'a> {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame[..])?;
+        self.0.push(frame);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::phy::{RxToken, TxToken};
+
+    #[test]
+    fn carries_frames_both_ways() {
+        let (mut a, mut b) = pair(PIPE_MTU);
+        let now = Instant::from_millis(0);
+        a.transmit()
+            .unwrap()
+            .consume(now, 3, |frame| {
+                frame.copy_from_slice(b"abc");
+                Ok(())
+            })
+            .unwrap();
+        assert!(a.receive().is_none());
+
+        let (rx, tx) = b.receive().unwrap();
+        rx.consume(now, |frame| {
+            assert_eq!(frame, b"abc");
+            Ok(())
+        })
+        .unwrap();
+        tx.consume(now, 1, |frame| {
+            frame[0] = b'x';
+            Ok(())
+        })
+        .unwrap();
+
+        let (rx, _) = a.receive().unwrap();
+        rx.consume(now, |frame| {
+            assert_eq!(frame, b"x");
+            Ok(())
+        })
+        .unwrap();
+    }
+}
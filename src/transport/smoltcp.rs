@@ -0,0 +1,591 @@
+use crate::transport::stack::{Stack, StackSocket};
+use crate::transport::{
+    bucket_prefix, check_deadline, deadline, parse_bucket_prefix, Listener, Transport,
+    TransportOptions, TransportStats, MARKER_REPEAT, STREAM_HELLO,
+};
+use ::smoltcp::socket::{
+    TcpSocket, TcpSocketBuffer, TcpState, UdpPacketMetadata, UdpSocket, UdpSocketBuffer,
+};
+use ::smoltcp::wire::IpEndpoint;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const RX_BUFFER_SIZE: usize = 32 * 1024 * 1024;
+/// Largest smoltcp TCP buffer a peer may ask for; a listener allocates both
+/// buffers up front for every socket it keeps listening.
+pub const MAX_TCP_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+/// Handshakes a smoltcp TCP listener keeps sockets for at once.
+const TCP_BACKLOG: usize = 8;
+/// Time a smoltcp TCP connection may take to be established.
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause before sending another SYN after a reset, as smoltcp resets SYNs
+/// that find no socket listening.
+const TCP_CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+/// Gap between the hellos of a new stream, so that the later ones go out once
+/// the first has resolved the server's MAC address.
+const HELLO_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Creates an unbound smoltcp UDP socket with `rx_size` bytes of receive
+/// buffer and `tx_size` bytes of transmit buffer, holding datagrams of up to
+/// `max_datagram_size` bytes.
+pub fn new_udp_socket(
+    rx_size: usize,
+    tx_size: usize,
+    max_datagram_size: usize,
+) -> UdpSocket<'static> {
+    let rx_packets = rx_size / max_datagram_size + 1;
+    let tx_packets = tx_size / max_datagram_size + 1;
+    let udp_rx_buffer =
+        UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; rx_packets], vec![0; rx_size]);
+    let udp_tx_buffer =
+        UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; tx_packets], vec![0; tx_size]);
+    UdpSocket::new(udp_rx_buffer, udp_tx_buffer)
+}
+
+/// Transmit buffer of the smoltcp UDP sockets of a stream: a whole bucket,
+/// and at least a datagram of the largest size so that hellos, markers and
+/// responses larger than the buckets fit too.
+fn udp_tx_buffer_size(options: &TransportOptions, stack: &Stack) -> usize {
+    options.bucket_size.max(stack.max_datagram_size())
+}
+
+/// Tuning of smoltcp TCP connections. The client picks it and sends it in
+/// the `PerfRequest` so that both ends run the same settings.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmoltcpTcpConfig {
+    /// Receive buffer, which bounds the advertised window.
+    pub rx_buffer_size: usize,
+    /// Send buffer, which bounds the data in flight.
+    pub tx_buffer_size: usize,
+    pub nagle: bool,
+    /// Delay of ACKs in milliseconds, 0 acknowledges every segment at once.
+    pub ack_delay: u64,
+}
+
+impl Default for SmoltcpTcpConfig {
+    fn default() -> Self {
+        SmoltcpTcpConfig {
+            rx_buffer_size: 4 * 1024 * 1024,
+            tx_buffer_size: 4 * 1024 * 1024,
+            nagle: true,
+            ack_delay: 10,
+        }
+    }
+}
+
+impl SmoltcpTcpConfig {
+    /// Checks the settings a peer asked for before opening connections with
+    /// them.
+    pub fn validate(&self) -> Result<(), String> {
+        for &size in &[self.rx_buffer_size, self.tx_buffer_size] {
+            if size == 0 || size > MAX_TCP_BUFFER_SIZE {
+                return Err(format!(
+                    "smoltcp tcp buffers must be between 1 and {} bytes",
+                    MAX_TCP_BUFFER_SIZE
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn new_socket(&self) -> TcpSocket<'static> {
+        let mut socket = TcpSocket::new(
+            TcpSocketBuffer::new(vec![0; self.rx_buffer_size]),
+            TcpSocketBuffer::new(vec![0; self.tx_buffer_size]),
+        );
+        socket.set_nagle_enabled(self.nagle);
+        socket.set_ack_delay(match self.ack_delay {
+            0 => None,
+            ack_delay => Some(::smoltcp::time::Duration::from_millis(ack_delay)),
+        });
+        socket
+    }
+}
+
+/// The stack smoltcp transports attach their sockets to.
+pub fn options_stack(options: &TransportOptions) -> io::Result<Arc<Stack>> {
+    options.stack.clone().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "smoltcp transports need a device, see --smoltcp_interface",
+        )
+    })
+}
+
+pub fn smoltcp_error(err: ::smoltcp::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("smoltcp error: {}", err))
+}
+
+/// smoltcp UDP socket sending buckets as datagrams that fit in one frame of
+/// the device. Empty datagrams mark the end of the test.
+pub struct SmoltcpUdpTransport {
+    socket: Arc<StackSocket>,
+    remote: IpEndpoint,
+    /// Longest `recv_bucket` waits for a datagram.
+    peer_timeout: Option<Duration>,
+    stats: TransportStats,
+}
+
+impl SmoltcpUdpTransport {
+    fn new(socket: StackSocket, remote: IpEndpoint, peer_timeout: Option<Duration>) -> Self {
+        SmoltcpUdpTransport {
+            socket: Arc::new(socket),
+            remote,
+            peer_timeout,
+            stats: TransportStats::default(),
+        }
+    }
+
+    fn send_datagram(&mut self, datagram: &[u8]) -> io::Result<()> {
+        let remote = self.remote;
+        loop {
+            let result = self
+                .socket
+                .with::<UdpSocket, _, _>(|socket| socket.send_slice(datagram, remote));
+            match result {
+                Ok(()) => return Ok(()),
+                Err(::smoltcp::Error::Exhausted) => std::thread::yield_now(),
+                Err(err) => return Err(smoltcp_error(err)),
+            }
+        }
+    }
+}
+
+impl Transport for SmoltcpUdpTransport {
+    fn connect(addr: &SocketAddr, options: &TransportOptions) -> io::Result<Self> {
+        let stack = options_stack(options)?;
+        let mut socket = new_udp_socket(
+            RX_BUFFER_SIZE,
+            udp_tx_buffer_size(options, &stack),
+            stack.max_datagram_size(),
+        );
+        socket
+            .bind(stack.ephemeral_port()?)
+            .map_err(smoltcp_error)?;
+
+        let mut transport =
+            SmoltcpUdpTransport::new(stack.add(socket), (*addr).into(), options.peer_timeout);
+        for _ in 0..MARKER_REPEAT {
+            transport.send_datagram(&STREAM_HELLO[..])?;
+            std::thread::sleep(HELLO_INTERVAL);
+        }
+        Ok(transport)
+    }
+
+    fn send_bucket(&mut self, bucket: &[u8]) -> io::Result<()> {
+        let max_datagram_size = self.socket.stack().max_datagram_size();
+        for datagram in bucket.chunks(max_datagram_size) {
+            self.send_datagram(datagram)?;
+        }
+
+        self.stats.send_nbytes += bucket.len();
+        self.stats.send_nbuckets += 1;
+        Ok(())
+    }
+
+    fn recv_bucket(&mut self, bucket: &mut [u8]) -> io::Result<Option<usize>> {
+        let deadline = deadline(self.peer_timeout);
+        let nbytes = loop {
+            let result = self
+                .socket
+                .with::<UdpSocket, _, _>(|socket| socket.recv_slice(bucket));
+            match result {
+                Ok((nbytes, _)) if bucket[..nbytes] == STREAM_HELLO[..] => continue,
+                Ok((nbytes, _)) => break nbytes,
+                Err(::smoltcp::Error::Exhausted) => {
+                    check_deadline(deadline, "a smoltcp udp datagram")?;
+                    std::thread::yield_now();
+                }
+                Err(err) => return Err(smoltcp_error(err)),
+            }
+        };
+        if nbytes == 0 {
+            return Ok(None);
+        }
+
+        self.stats.recv_nbytes += nbytes;
+        self.stats.recv_nbuckets += 1;
+        Ok(Some(nbytes))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        for _ in 0..MARKER_REPEAT {
+            self.send_datagram(&[])?;
+        }
+        Ok(())
+    }
+
+    /// The socket is detached from the stack once both halves are dropped,
+    /// after the end markers had time to leave.
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn Transport>, Box<dyn Transport>)> {
+        let other = SmoltcpUdpTransport {
+            socket: self.socket.clone(),
+            remote: self.remote,
+            peer_timeout: self.peer_timeout,
+            stats: TransportStats::default(),
+        };
+        Ok((self, Box::new(other)))
+    }
+
+    fn stats(&self) -> TransportStats {
+        self.stats
+    }
+}
+
+/// Binds a smoltcp UDP socket and hands it out to the first remote endpoint
+/// that says hello.
+pub struct SmoltcpUdpTransportListener {
+    socket: Option<StackSocket>,
+    local_addr: SocketAddr,
+    accept_timeout: Option<Duration>,
+    peer_timeout: Option<Duration>,
+}
+
+impl Listener for SmoltcpUdpTransportListener {
+    type Transport = SmoltcpUdpTransport;
+
+    /// Binds on the address of the stack; only the port of `addr` is used.
+    fn bind(addr: &SocketAddr, options: &TransportOptions) -> io::Result<Self> {
+        let stack = options_stack(options)?;
+        let port = match addr.port() {
+            0 => stack.ephemeral_port()?,
+            port => port,
+        };
+        let local_addr = SocketAddr::new(stack.address(), port);
+        let mut socket = new_udp_socket(
+            RX_BUFFER_SIZE,
+            udp_tx_buffer_size(options, &stack),
+            stack.max_datagram_size(),
+        );
+        socket.bind(port).map_err(smoltcp_error)?;
+
+        Ok(SmoltcpUdpTransportListener {
+            socket: Some(stack.add(socket)),
+            local_addr,
+            accept_timeout: options.accept_timeout,
+            peer_timeout: options.peer_timeout,
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn accept(&mut self) -> io::Result<SmoltcpUdpTransport> {
+        let socket = self.socket.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "smoltcp udp socket is already in use",
+            )
+        })?;
+
+        let deadline = deadline(self.accept_timeout);
+        let mut datagram = vec![0; 64 * 1024];
+        let remote = loop {
+            match socket.with::<UdpSocket, _, _>(|socket| socket.recv_slice(&mut datagram[..])) {
+                Ok((nbytes, remote)) if datagram[..nbytes] == STREAM_HELLO[..] => break remote,
+                Ok(_) => {}
+                Err(::smoltcp::Error::Exhausted) => {
+                    check_deadline(deadline, "a smoltcp udp stream hello")?;
+                    std::thread::yield_now();
+                }
+                Err(err) => return Err(smoltcp_error(err)),
+            }
+        };
+
+        let socket = self.socket.take().unwrap();
+        Ok(SmoltcpUdpTransport::new(socket, remote, self.peer_timeout))
+    }
+}
+
+/// Byte stream over a smoltcp TCP socket. Calls spin until the poll thread
+/// has moved data, like the UDP transport does.
+struct SmoltcpTcpStream {
+    socket: Arc<StackSocket>,
+    /// Longest a call waits for the peer to make progress.
+    peer_timeout: Option<Duration>,
+}
+
+impl Read for SmoltcpTcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = deadline(self.peer_timeout);
+        loop {
+            let result = self.socket.with::<TcpSocket, _, _>(|socket| {
+                if socket.can_recv() {
+                    socket.recv_slice(buf).map(Some).map_err(smoltcp_error)
+                } else if socket.may_recv() {
+                    Ok(None)
+                } else {
+                    // The peer closed its end.
+                    Ok(Some(0))
+                }
+            });
+            match result? {
+                Some(nbytes) => return Ok(nbytes),
+                None => {
+                    check_deadline(deadline, "data from the smoltcp tcp peer")?;
+                    std::thread::yield_now();
+                }
+            }
+        }
+    }
+}
+
+impl Write for SmoltcpTcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let deadline = deadline(self.peer_timeout);
+        loop {
+            let result = self.socket.with::<TcpSocket, _, _>(|socket| {
+                if !socket.may_send() {
+                    Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        format!("smoltcp tcp connection is {}", socket.state()),
+                    ))
+                } else if socket.can_send() {
+                    socket.send_slice(buf).map(Some).map_err(smoltcp_error)
+                } else {
+                    Ok(None)
+                }
+            });
+            match result? {
+                Some(nbytes) => return Ok(nbytes),
+                None => {
+                    check_deadline(deadline, "the smoltcp tcp peer to take data")?;
+                    std::thread::yield_now();
+                }
+            }
+        }
+    }
+
+    /// Waits until the peer acknowledged everything written.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut send_queue = usize::MAX;
+        let mut stalled_until = deadline(self.peer_timeout);
+        loop {
+            let queued = self.socket.with::<TcpSocket, _, _>(|socket| {
+                if socket.may_send() {
+                    socket.send_queue()
+                } else {
+                    0
+                }
+            });
+            if queued == 0 {
+                break;
+            }
+            if queued < send_queue {
+                send_queue = queued;
+                stalled_until = deadline(self.peer_timeout);
+            }
+            check_deadline(stalled_until, "the smoltcp tcp peer to acknowledge data")?;
+            std::thread::yield_now();
+        }
+        Ok(())
+    }
+}
+
+/// smoltcp TCP connection carrying length-prefixed buckets, like the kernel
+/// TCP transport.
+pub struct SmoltcpTcpTransport {
+    stream: SmoltcpTcpStream,
+    stats: TransportStats,
+}
+
+impl SmoltcpTcpTransport {
+    fn new(socket: Arc<StackSocket>, peer_timeout: Option<Duration>) -> Self {
+        SmoltcpTcpTransport {
+            stream: SmoltcpTcpStream {
+                socket,
+                peer_timeout,
+            },
+            stats: TransportStats::default(),
+        }
+    }
+}
+
+impl Transport for SmoltcpTcpTransport {
+    fn connect(addr: &SocketAddr, options: &TransportOptions) -> io::Result<Self> {
+        let stack = options_stack(options)?;
+        let remote = IpEndpoint::from(*addr);
+        let mut socket = options.smoltcp_tcp.new_socket();
+        socket
+            .connect(remote, stack.ephemeral_port()?)
+            .map_err(smoltcp_error)?;
+        let socket = stack.add(socket);
+
+        let start = Instant::now();
+        loop {
+            match socket.with::<TcpSocket, _, _>(|socket| socket.state()) {
+                TcpState::Established => break,
+                TcpState::Closed if start.elapsed() > TCP_CONNECT_TIMEOUT => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        format!("smoltcp tcp connection to {} refused", addr),
+                    ))
+                }
+                // Listeners keep a single socket spare, so a SYN arriving
+                // together with another one may be reset.
+                TcpState::Closed => {
+                    std::thread::sleep(TCP_CONNECT_RETRY_INTERVAL);
+                    let port = stack.ephemeral_port()?;
+                    socket
+                        .with::<TcpSocket, _, _>(|socket| socket.connect(remote, port))
+                        .map_err(smoltcp_error)?;
+                }
+                _ if start.elapsed() > TCP_CONNECT_TIMEOUT => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("smoltcp tcp connection to {} timed out", addr),
+                    ))
+                }
+                _ => std::thread::yield_now(),
+            }
+        }
+        Ok(SmoltcpTcpTransport::new(
+            Arc::new(socket),
+            options.peer_timeout,
+        ))
+    }
+
+    fn send_bucket(&mut self, bucket: &[u8]) -> io::Result<()> {
+        self.stream.write_all(&bucket_prefix(bucket.len())[..])?;
+        self.stream.write_all(bucket)?;
+
+        self.stats.send_nbytes += bucket.len();
+        self.stats.send_nbuckets += 1;
+        Ok(())
+    }
+
+    fn recv_bucket(&mut self, bucket: &mut [u8]) -> io::Result<Option<usize>> {
+        let mut prefix = [0; 8];
+        self.stream.read_exact(&mut prefix[..])?;
+        let nbytes = match parse_bucket_prefix(prefix, bucket.len())? {
+            Some(nbytes) => nbytes,
+            None => return Ok(None),
+        };
+        self.stream.read_exact(&mut bucket[..nbytes])?;
+
+        self.stats.recv_nbytes += nbytes;
+        self.stats.recv_nbuckets += 1;
+        Ok(Some(nbytes))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.stream.write_all(&bucket_prefix(0)[..])?;
+        self.stream.flush()
+    }
+
+    /// Sends a FIN; the socket is detached from the stack once both halves
+    /// are dropped.
+    fn close(&mut self) -> io::Result<()> {
+        self.stream
+            .socket
+            .with::<TcpSocket, _, _>(|socket| socket.close());
+        Ok(())
+    }
+
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn Transport>, Box<dyn Transport>)> {
+        let other = SmoltcpTcpTransport::new(self.stream.socket.clone(), self.stream.peer_timeout);
+        Ok((self, Box::new(other)))
+    }
+
+    fn stats(&self) -> TransportStats {
+        self.stats
+    }
+}
+
+/// Keeps a smoltcp TCP socket listening on one port and hands out
+/// connections as they get established. Each socket that receives a SYN is
+/// replaced by a new listening one, up to `TCP_BACKLOG` pending handshakes.
+pub struct SmoltcpTcpTransportListener {
+    stack: Arc<Stack>,
+    config: SmoltcpTcpConfig,
+    local_addr: SocketAddr,
+    backlog: Vec<StackSocket>,
+    accept_timeout: Option<Duration>,
+    peer_timeout: Option<Duration>,
+}
+
+impl SmoltcpTcpTransportListener {
+    fn listen(&self) -> io::Result<StackSocket> {
+        let mut socket = self.config.new_socket();
+        socket
+            .listen(self.local_addr.port())
+            .map_err(smoltcp_error)?;
+        Ok(self.stack.add(socket))
+    }
+}
+
+impl Listener for SmoltcpTcpTransportListener {
+    type Transport = SmoltcpTcpTransport;
+
+    /// Listens on the address of the stack; only the port of `addr` is used.
+    fn bind(addr: &SocketAddr, options: &TransportOptions) -> io::Result<Self> {
+        let stack = options_stack(options)?;
+        let port = match addr.port() {
+            0 => stack.ephemeral_port()?,
+            port => port,
+        };
+        let mut listener = SmoltcpTcpTransportListener {
+            local_addr: SocketAddr::new(stack.address(), port),
+            stack,
+            config: options.smoltcp_tcp,
+            backlog: Vec::new(),
+            accept_timeout: options.accept_timeout,
+            peer_timeout: options.peer_timeout,
+        };
+        let socket = listener.listen()?;
+        listener.backlog.push(socket);
+        Ok(listener)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn accept(&mut self) -> io::Result<SmoltcpTcpTransport> {
+        let deadline = deadline(self.accept_timeout);
+        loop {
+            // Sockets whose handshake failed are closed for good.
+            self.backlog.retain(|socket| {
+                socket.with::<TcpSocket, _, _>(|socket| socket.state() != TcpState::Closed)
+            });
+            let states: Vec<TcpState> = self
+                .backlog
+                .iter()
+                .map(|socket| socket.with::<TcpSocket, _, _>(|socket| socket.state()))
+                .collect();
+            if !states.contains(&TcpState::Listen) && self.backlog.len() < TCP_BACKLOG {
+                let socket = self.listen()?;
+                self.backlog.push(socket);
+            }
+            let established = states
+                .iter()
+                .position(|state| matches!(state, TcpState::Established | TcpState::CloseWait));
+            if let Some(index) = established {
+                let connection = self.backlog.remove(index);
+                return Ok(SmoltcpTcpTransport::new(
+                    Arc::new(connection),
+                    self.peer_timeout,
+                ));
+            }
+            check_deadline(deadline, "a smoltcp tcp connection")?;
+            std::thread::yield_now();
+        }
+    }
+}
+
+impl Drop for SmoltcpTcpTransportListener {
+    fn drop(&mut self) {
+        for socket in &self.backlog {
+            socket.with::<TcpSocket, _, _>(|socket| socket.abort());
+        }
+    }
+}
@@ -0,0 +1,629 @@
+//! User-space network stack driving the smoltcp transports.
+//!
+//! A smoltcp `Interface` is bound to a `phy::Device` and polled by a
+//! dedicated thread, which owns both: the TAP and raw socket devices are not
+//! `Send`, so they are opened on that thread too. Transports only touch the
+//! shared `SocketSet`.
+//!
+//! Between the interface and the device, frames may go through a pcap
+//! writer, which records them as the stack sees them, and through emulated
+//! impairments.
+
+use crate::transport::impair::ImpairmentConfig;
+use crate::transport::pipe::PipeDevice;
+use crate::utils;
+use smoltcp::iface::{Interface, InterfaceBuilder, NeighborCache, Routes};
+use smoltcp::phy::{
+    self, Device, Medium, PcapMode, PcapSink, PcapWriter, RawSocket, TunTapInterface,
+};
+use smoltcp::socket::{AnySocket, Socket, SocketHandle, SocketSet};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+/// Longest the poll thread sleeps while nothing happens on the device, so
+/// that data queued by the transports goes out promptly.
+const MAX_POLL_WAIT_MS: u64 = 1;
+/// Time a dropped socket stays in the set so its queued datagrams still go out.
+const SOCKET_LINGER: std::time::Duration = std::time::Duration::from_secs(1);
+/// Time between two looks for lingering sockets to detach, busy or not.
+const LINGER_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+/// First local port handed out to sockets without an explicit one.
+const EPHEMERAL_PORT_START: u16 = 49152;
+/// MAC of the stack on a TAP device, whose kernel end has its own.
+const TAP_ETHERNET_ADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x01]);
+/// Ethernet, IPv4 and UDP headers in front of a datagram.
+const UDP_FRAME_OVERHEAD: usize = 14 + 20 + 8;
+
+/// Device the stack sends and receives its frames on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// A TAP device; the stack is a separate host on its link and needs an
+    /// address of its own.
+    Tap,
+    /// A raw socket bound to an existing interface. The stack shares the
+    /// interface's address and MAC unless given others, so the kernel sees
+    /// its traffic too and answers it with TCP resets and ICMP errors
+    /// unless firewalled, e.g. by dropping the test's ports in iptables.
+    Raw,
+}
+
+impl FromStr for DeviceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tap" => Ok(DeviceKind::Tap),
+            "raw" => Ok(DeviceKind::Raw),
+            _ => Err(format!(
+                "unknown smoltcp device {:?}, expected tap or raw",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DeviceKind::Tap => "tap",
+            DeviceKind::Raw => "raw",
+        })
+    }
+}
+
+/// Device and addressing of the stack. Whatever is left out is derived from
+/// `interface` through `utils::find_interfaces` and the kernel's tables.
+#[derive(Debug, Clone)]
+pub struct StackConfig {
+    pub device: DeviceKind,
+    /// Name of the TAP device, or of the interface the raw socket binds to.
+    pub interface: String,
+    /// Address and prefix of the stack. Required on a TAP device, whose
+    /// interface address belongs to the kernel; by default that of
+    /// `interface` on a raw socket.
+    pub ip_cidr: Option<IpCidr>,
+    /// Default gateway, by default that of `interface`.
+    pub gateway: Option<Ipv4Addr>,
+    /// By default the MAC of `interface` for raw sockets.
+    pub ethernet_addr: Option<EthernetAddress>,
+    /// Emulated network conditions between the stack and its device.
+    pub impairment: ImpairmentConfig,
+    /// File every frame the stack sends or receives is written to.
+    pub pcap: Option<PathBuf>,
+}
+
+impl Default for StackConfig {
+    fn default() -> Self {
+        StackConfig {
+            device: DeviceKind::Tap,
+            interface: String::new(),
+            ip_cidr: None,
+            gateway: None,
+            ethernet_addr: None,
+            impairment: ImpairmentConfig::default(),
+            pcap: None,
+        }
+    }
+}
+
+/// Addresses of a stack once the defaults are filled in.
+#[derive(Debug, Clone, Copy)]
+struct StackAddrs {
+    ip_cidr: IpCidr,
+    address: Ipv4Addr,
+    gateway: Option<Ipv4Addr>,
+    ethernet_addr: EthernetAddress,
+}
+
+impl StackConfig {
+    fn resolve(&self) -> io::Result<StackAddrs> {
+        let ip_cidr = match (self.ip_cidr, self.device) {
+            (Some(ip_cidr), _) => ip_cidr,
+            (None, DeviceKind::Tap) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "the smoltcp stack on tap device {} needs an address of its own, \
+                         see --smoltcp_ip",
+                        self.interface
+                    ),
+                ))
+            }
+            (None, DeviceKind::Raw) => utils::find_interfaces()
+                .into_iter()
+                .find(|dev| dev.interface_name == self.interface)
+                .map(|dev| dev.ip_cidr)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no address found for interface {}", self.interface),
+                    )
+                })?,
+        };
+        let address = match ip_cidr.address() {
+            IpAddress::Ipv4(address) => Ipv4Addr::from(address.0),
+            address => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("smoltcp stack needs an ipv4 address, got {}", address),
+                ))
+            }
+        };
+
+        let ethernet_addr = match (self.ethernet_addr, self.device) {
+            (Some(ethernet_addr), _) => ethernet_addr,
+            (None, DeviceKind::Tap) => TAP_ETHERNET_ADDR,
+            (None, DeviceKind::Raw) => utils::get_net_if_mac(&self.interface)
+                .and_then(|mac| mac.parse().ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no MAC address found for interface {}", self.interface),
+                    )
+                })?,
+        };
+
+        Ok(StackAddrs {
+            ip_cidr,
+            address,
+            gateway: self
+                .gateway
+                .or_else(|| utils::get_net_if_gateway(&self.interface)),
+            ethernet_addr,
+        })
+    }
+}
+
+/// Wakes the poll thread of a stack whose device has no file descriptor to
+/// wait on, when frames arrive or a transport queued data.
+#[derive(Debug, Default)]
+pub struct Waker {
+    pending: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        *self.pending.lock().unwrap() = true;
+        self.cond.notify_one();
+    }
+
+    /// Blocks until woken or for at most `timeout`.
+    pub fn wait(&self, timeout: std::time::Duration) {
+        let pending = self.pending.lock().unwrap();
+        let (mut pending, _) = self
+            .cond
+            .wait_timeout_while(pending, timeout, |pending| !*pending)
+            .unwrap();
+        *pending = false;
+    }
+}
+
+/// A running smoltcp interface and the sockets attached to it. The poll
+/// thread stops when the stack is dropped.
+pub struct Stack {
+    description: String,
+    address: Ipv4Addr,
+    mtu: usize,
+    sockets: Arc<Mutex<SocketSet<'static>>>,
+    lingering: Arc<Mutex<Vec<(SocketHandle, std::time::Instant)>>>,
+    waker: Arc<Waker>,
+    next_port: AtomicU16,
+    closed: Arc<AtomicBool>,
+    poller: Option<JoinHandle<()>>,
+}
+
+impl fmt::Debug for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stack")
+            .field("description", &self.description)
+            .field("address", &self.address)
+            .field("mtu", &self.mtu)
+            .finish()
+    }
+}
+
+impl Stack {
+    /// Opens the device of `config` and starts polling it.
+    pub fn start(config: &StackConfig) -> io::Result<Arc<Stack>> {
+        let addrs = config.resolve()?;
+        let description = format!("device={} interface={}", config.device, config.interface);
+        let interface = config.interface.clone();
+        let impairment = config.impairment.clone();
+        let pcap = config.pcap.as_deref();
+        match config.device {
+            DeviceKind::Tap => Stack::spawn(
+                description,
+                addrs,
+                impairment,
+                pcap,
+                Arc::default(),
+                move || {
+                    let device = TunTapInterface::new(&interface, Medium::Ethernet)?;
+                    let fd = device.as_raw_fd();
+                    Ok((device, Some(fd)))
+                },
+            ),
+            DeviceKind::Raw => Stack::spawn(
+                description,
+                addrs,
+                impairment,
+                pcap,
+                Arc::default(),
+                move || {
+                    let device = RawSocket::new(&interface)?;
+                    let fd = device.as_raw_fd();
+                    Ok((device, Some(fd)))
+                },
+            ),
+        }
+    }
+
+    /// Runs a stack at `ip_cidr` on one end of an in-memory pipe.
+    pub fn start_pipe(
+        device: PipeDevice,
+        ip_cidr: IpCidr,
+        ethernet_addr: EthernetAddress,
+        impairment: &ImpairmentConfig,
+        pcap: Option<&Path>,
+    ) -> io::Result<Arc<Stack>> {
+        let address = match ip_cidr.address() {
+            IpAddress::Ipv4(address) => Ipv4Addr::from(address.0),
+            address => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("smoltcp stack needs an ipv4 address, got {}", address),
+                ))
+            }
+        };
+        let addrs = StackAddrs {
+            ip_cidr,
+            address,
+            gateway: None,
+            ethernet_addr,
+        };
+        let waker = device.waker();
+        Stack::spawn(
+            "device=pipe".to_string(),
+            addrs,
+            impairment.clone(),
+            pcap,
+            waker,
+            move || Ok((device, None)),
+        )
+    }
+
+    /// Starts the poll thread, which opens its device with `open`, and waits
+    /// for the interface to be up. The thread waits for frames on the file
+    /// descriptor `open` returns, or on `waker` for devices without one.
+    fn spawn<D, F>(
+        description: String,
+        addrs: StackAddrs,
+        impairment: ImpairmentConfig,
+        pcap: Option<&Path>,
+        waker: Arc<Waker>,
+        open: F,
+    ) -> io::Result<Arc<Stack>>
+    where
+        D: for<'d> Device<'d>,
+        F: FnOnce() -> io::Result<(D, Option<RawFd>)> + Send + 'static,
+    {
+        impairment
+            .validate()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let impaired = impairment.is_active();
+        let mut description = description;
+        if impaired {
+            description = format!("{} impairment=({})", description, impairment);
+        }
+        let pcap = match pcap {
+            Some(path) => {
+                let file = File::create(path).map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!("smoltcp pcap {}: {}", path.display(), err),
+                    )
+                })?;
+                description = format!("{} pcap={}", description, path.display());
+                Some(file)
+            }
+            None => None,
+        };
+
+        let sockets = Arc::new(Mutex::new(SocketSet::new(vec![])));
+        let lingering = Arc::new(Mutex::new(Vec::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let poller = Poller {
+            sockets: sockets.clone(),
+            lingering: lingering.clone(),
+            waker: waker.clone(),
+            closed: closed.clone(),
+        };
+        let poller = std::thread::spawn(move || match open() {
+            Ok((device, fd)) if impaired => {
+                poller.capture(impairment.wrap(device), fd, pcap, &addrs, &ready_tx)
+            }
+            Ok((device, fd)) => poller.capture(device, fd, pcap, &addrs, &ready_tx),
+            Err(err) => {
+                let _ = ready_tx.send(Err(err));
+            }
+        });
+        let mtu = ready_rx
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "smoltcp poll thread exited"))??;
+        // On stderr, as clients may print a --json document on stdout.
+        eprintln!(
+            "smoltcp {} address={} mtu={}",
+            description, addrs.ip_cidr, mtu
+        );
+
+        Ok(Arc::new(Stack {
+            description,
+            address: addrs.address,
+            mtu,
+            sockets,
+            lingering,
+            waker,
+            next_port: AtomicU16::new(EPHEMERAL_PORT_START),
+            closed,
+            poller: Some(poller),
+        }))
+    }
+
+    /// Address the stack answers on.
+    pub fn address(&self) -> IpAddr {
+        IpAddr::V4(self.address)
+    }
+
+    /// Largest UDP payload that fits in one frame of the device.
+    pub fn max_datagram_size(&self) -> usize {
+        self.mtu.saturating_sub(UDP_FRAME_OVERHEAD)
+    }
+
+    /// A local port no socket of this stack holds. Ports are handed out in
+    /// turn, so one is only reused once the counter wraps and its socket is
+    /// gone.
+    pub fn ephemeral_port(&self) -> io::Result<u16> {
+        let in_use = self
+            .sockets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(local_port)
+            .collect::<HashSet<_>>();
+        for _ in 0..=u16::MAX {
+            let port = self.next_port.fetch_add(1, Ordering::Relaxed);
+            if port >= EPHEMERAL_PORT_START && !in_use.contains(&port) {
+                return Ok(port);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("smoltcp {}: no ephemeral port left", self.description),
+        ))
+    }
+
+    /// Attaches `socket` to the interface.
+    pub fn add<T: Into<Socket<'static>>>(self: &Arc<Self>, socket: T) -> StackSocket {
+        let handle = self.sockets.lock().unwrap().add(socket);
+        StackSocket {
+            stack: self.clone(),
+            handle,
+        }
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.waker.wake();
+        if let Some(poller) = self.poller.take() {
+            let _ = poller.join();
+        }
+    }
+}
+
+/// A socket attached to a `Stack`. It is detached a while after being
+/// dropped, once what it queued had a chance to leave.
+pub struct StackSocket {
+    stack: Arc<Stack>,
+    handle: SocketHandle,
+}
+
+impl StackSocket {
+    pub fn stack(&self) -> &Arc<Stack> {
+        &self.stack
+    }
+
+    /// Calls `f` on the socket, with the socket set locked, and wakes the
+    /// poll thread for whatever `f` queued.
+    pub fn with<T, R, F>(&self, f: F) -> R
+    where
+        T: AnySocket<'static>,
+        F: FnOnce(&mut T) -> R,
+    {
+        let result = {
+            let mut sockets = self.stack.sockets.lock().unwrap();
+            let mut socket = sockets.get::<T>(self.handle);
+            f(&mut *socket)
+        };
+        self.stack.waker.wake();
+        result
+    }
+}
+
+impl Drop for StackSocket {
+    fn drop(&mut self) {
+        self.stack
+            .lingering
+            .lock()
+            .unwrap()
+            .push((self.handle, std::time::Instant::now()));
+    }
+}
+
+/// State of the poll thread.
+struct Poller {
+    sockets: Arc<Mutex<SocketSet<'static>>>,
+    lingering: Arc<Mutex<Vec<(SocketHandle, std::time::Instant)>>>,
+    waker: Arc<Waker>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Poller {
+    /// Runs the stack on `device`, recording its frames to `pcap` if given.
+    fn capture<D>(
+        self,
+        device: D,
+        fd: Option<RawFd>,
+        pcap: Option<File>,
+        addrs: &StackAddrs,
+        ready: &mpsc::Sender<io::Result<usize>>,
+    ) where
+        D: for<'d> Device<'d>,
+    {
+        match pcap {
+            Some(file) => {
+                let sink = Rc::new(RefCell::new(BufWriter::new(file)));
+                let writer =
+                    PcapWriter::new(device, sink.clone() as Rc<dyn PcapSink>, PcapMode::Both);
+                self.run(writer, fd, Some(&sink), addrs, ready);
+            }
+            None => self.run(device, fd, None, addrs, ready),
+        }
+    }
+
+    /// Builds the interface on `device`, reports its MTU on `ready` and polls
+    /// it until the stack is dropped. Captured frames are flushed to `pcap`
+    /// whenever the stack goes idle, so the file is readable while it runs.
+    fn run<D>(
+        self,
+        device: D,
+        fd: Option<RawFd>,
+        pcap: Option<&RefCell<BufWriter<File>>>,
+        addrs: &StackAddrs,
+        ready: &mpsc::Sender<io::Result<usize>>,
+    ) where
+        D: for<'d> Device<'d>,
+    {
+        let mtu = device.capabilities().max_transmission_unit;
+        let mut iface = match build_interface(device, addrs) {
+            Ok(iface) => iface,
+            Err(err) => {
+                let _ = ready.send(Err(err));
+                return;
+            }
+        };
+        let _ = ready.send(Ok(mtu));
+
+        let mut last_expiry = std::time::Instant::now();
+        while !self.closed.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            let (activity, delay) = {
+                let mut sockets = self.sockets.lock().unwrap();
+                let activity = match iface.poll(&mut sockets, now) {
+                    Ok(activity) => activity,
+                    Err(err) => {
+                        tracing::debug!("smoltcp poll: {}", err);
+                        true
+                    }
+                };
+                (activity, iface.poll_delay(&sockets, now))
+            };
+            if last_expiry.elapsed() >= LINGER_CHECK_INTERVAL {
+                self.expire_lingering();
+                last_expiry = std::time::Instant::now();
+            }
+            if !activity {
+                if let Some(pcap) = pcap {
+                    if let Err(err) = pcap.borrow_mut().flush() {
+                        tracing::warn!("smoltcp pcap: {}", err);
+                    }
+                }
+                let max_wait = Duration::from_millis(MAX_POLL_WAIT_MS);
+                let delay = delay.map_or(max_wait, |delay| delay.min(max_wait));
+                self.wait(fd, delay);
+            }
+        }
+    }
+
+    /// Blocks for at most `delay`, until frames arrive on `fd` or, for
+    /// devices without one, until the waker is woken.
+    fn wait(&self, fd: Option<RawFd>, delay: Duration) {
+        match fd {
+            Some(fd) => {
+                if let Err(err) = phy::wait(fd, Some(delay)) {
+                    tracing::warn!("smoltcp wait: {}", err);
+                }
+            }
+            None => self
+                .waker
+                .wait(std::time::Duration::from_millis(delay.total_millis())),
+        }
+    }
+
+    fn expire_lingering(&self) {
+        let mut lingering = self.lingering.lock().unwrap();
+        if lingering.is_empty() {
+            return;
+        }
+        let mut sockets = self.sockets.lock().unwrap();
+        lingering.retain(|&(handle, dropped)| {
+            if dropped.elapsed() < SOCKET_LINGER {
+                return true;
+            }
+            sockets.remove(handle);
+            false
+        });
+    }
+}
+
+/// Local port of `socket`, zero while it has none, or `None` for kinds of
+/// sockets this crate does not open.
+fn local_port(socket: &Socket) -> Option<u16> {
+    match socket {
+        Socket::Udp(socket) => Some(socket.endpoint().port),
+        Socket::Tcp(socket) => Some(socket.local_endpoint().port),
+        _ => None,
+    }
+}
+
+fn build_interface<D>(device: D, addrs: &StackAddrs) -> io::Result<Interface<'static, D>>
+where
+    D: for<'d> Device<'d>,
+{
+    let mut routes = Routes::new(BTreeMap::new());
+    if let Some(gateway) = addrs.gateway {
+        routes
+            .add_default_ipv4_route(Ipv4Address(gateway.octets()))
+            .map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("smoltcp default route via {}: {}", gateway, err),
+                )
+            })?;
+    }
+
+    Ok(InterfaceBuilder::new(device)
+        .ethernet_addr(addrs.ethernet_addr)
+        .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+        .ip_addrs(vec![addrs.ip_cidr])
+        .routes(routes)
+        .finalize())
+}
@@ -0,0 +1,233 @@
+use crate::transport::{
+    bucket_prefix, parse_bucket_prefix, Listener, Transport, TransportOptions, TransportStats,
+};
+use nix::libc;
+use socket2::SockRef;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+
+pub fn nonblocking_write_all<W: Write>(stream: &mut W, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        match stream.write(buf) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ));
+            }
+            Ok(n) => buf = &buf[n..],
+            Err(ref e)
+                if e.kind() == io::ErrorKind::Interrupted
+                    || e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        std::thread::yield_now();
+    }
+    Ok(())
+}
+
+pub fn nonblocking_read_exact<R: Read>(stream: &mut R, mut buf: &mut [u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        match stream.read(buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                let tmp = buf;
+                buf = &mut tmp[n..];
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::Interrupted
+                    || e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        std::thread::yield_now();
+    }
+    if !buf.is_empty() {
+        Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "failed to fill whole buffer",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Buckets up to this size are written in one go with their prefix, so that
+/// Nagle's algorithm does not hold a small bucket back until the prefix is
+/// acknowledged, which would add a delayed ACK to every request/response
+/// transaction.
+const COALESCE_SIZE: usize = 64 * 1024;
+
+/// Kernel TCP stream carrying length-prefixed buckets.
+pub struct TcpTransport {
+    stream: TcpStream,
+    /// Prefix and bucket of a small bucket being sent.
+    frame: Vec<u8>,
+    stats: TransportStats,
+}
+
+impl TcpTransport {
+    /// Wraps `stream`, capping it at the kernel pacing rate of `options` and
+    /// bounding its reads and writes by the peer timeout.
+    fn with_options(stream: TcpStream, options: &TransportOptions) -> io::Result<Self> {
+        stream.set_read_timeout(options.peer_timeout)?;
+        stream.set_write_timeout(options.peer_timeout)?;
+        if let Some(rate) = options.pacing_rate {
+            set_max_pacing_rate(&stream, rate)?;
+        }
+        Ok(TcpTransport::new(stream))
+    }
+
+    pub fn new(stream: TcpStream) -> Self {
+        TcpTransport {
+            stream,
+            frame: Vec::new(),
+            stats: TransportStats::default(),
+        }
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl Transport for TcpTransport {
+    fn connect(addr: &SocketAddr, options: &TransportOptions) -> io::Result<Self> {
+        TcpTransport::with_options(TcpStream::connect(addr)?, options)
+    }
+
+    fn send_bucket(&mut self, bucket: &[u8]) -> io::Result<()> {
+        if bucket.len() <= COALESCE_SIZE {
+            self.frame.clear();
+            self.frame
+                .extend_from_slice(&bucket_prefix(bucket.len())[..]);
+            self.frame.extend_from_slice(bucket);
+            self.stream.write_all(&self.frame[..])?;
+        } else {
+            self.stream.write_all(&bucket_prefix(bucket.len())[..])?;
+            self.stream.write_all(bucket)?;
+        }
+
+        self.stats.send_nbytes += bucket.len();
+        self.stats.send_nbuckets += 1;
+        Ok(())
+    }
+
+    fn recv_bucket(&mut self, bucket: &mut [u8]) -> io::Result<Option<usize>> {
+        let mut prefix = [0; 8];
+        self.stream.read_exact(&mut prefix[..])?;
+        let nbytes = match parse_bucket_prefix(prefix, bucket.len())? {
+            Some(nbytes) => nbytes,
+            None => return Ok(None),
+        };
+        self.stream.read_exact(&mut bucket[..nbytes])?;
+
+        self.stats.recv_nbytes += nbytes;
+        self.stats.recv_nbuckets += 1;
+        Ok(Some(nbytes))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.stream.write_all(&bucket_prefix(0)[..])?;
+        self.stream.flush()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        match self.stream.shutdown(Shutdown::Both) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            result => result,
+        }
+    }
+
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn Transport>, Box<dyn Transport>)> {
+        let other = TcpTransport::new(self.stream.try_clone()?);
+        Ok((self, Box::new(other)))
+    }
+
+    fn stats(&self) -> TransportStats {
+        self.stats
+    }
+
+    fn retransmits(&self) -> Option<u64> {
+        tcp_info(&self.stream)
+            .ok()
+            .map(|info| info.tcpi_total_retrans as u64)
+    }
+}
+
+/// Reads the kernel's `TCP_INFO` for `stream`.
+fn tcp_info(stream: &TcpStream) -> io::Result<libc::tcp_info> {
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(info)
+}
+
+/// Sets `SO_MAX_PACING_RATE`, in bytes per second, on `stream`. The kernel
+/// spreads the packets of each write over time instead of sending bursts.
+fn set_max_pacing_rate(stream: &TcpStream, rate: u64) -> io::Result<()> {
+    // Since Linux 4.13 the option takes a 64-bit rate; as an unsigned int,
+    // rates of 2^32 - 1 and more would mean no limit.
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MAX_PACING_RATE,
+            &rate as *const u64 as *const libc::c_void,
+            std::mem::size_of::<u64>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub struct TcpTransportListener {
+    listener: TcpListener,
+    options: TransportOptions,
+}
+
+impl Listener for TcpTransportListener {
+    type Transport = TcpTransport;
+
+    fn bind(addr: &SocketAddr, options: &TransportOptions) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        // Linux applies the receive timeout of a listening socket to accept.
+        SockRef::from(&listener).set_read_timeout(options.accept_timeout)?;
+        Ok(TcpTransportListener {
+            listener,
+            options: options.clone(),
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn accept(&mut self) -> io::Result<TcpTransport> {
+        let (stream, _) = self.listener.accept().map_err(|err| {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out waiting for a tcp connection",
+                )
+            } else {
+                err
+            }
+        })?;
+        TcpTransport::with_options(stream, &self.options)
+    }
+}
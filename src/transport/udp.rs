@@ -0,0 +1,312 @@
+use crate::report::UdpStats;
+use crate::transport::{
+    bind_udp_socket, check_deadline, deadline, unspecified_addr, Listener, Transport,
+    TransportOptions, TransportStats, MARKER_REPEAT, STREAM_HELLO,
+};
+use std::collections::BTreeSet;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Largest payload of a single UDP datagram over IPv4.
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+/// Silence after which the receiver assumes the end-of-test datagram was lost.
+const END_TIMEOUT: Duration = Duration::from_secs(10);
+/// Every data datagram starts with a big-endian `u64` sequence number and
+/// the `u64` send time in microseconds since the Unix epoch.
+const DATAGRAM_HEADER_SIZE: usize = 16;
+/// Largest part of a bucket carried by one datagram.
+pub const MAX_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - DATAGRAM_HEADER_SIZE;
+/// Payload of a datagram filling a 1500-byte Ethernet MTU, so that loss is
+/// measured on whole datagrams rather than on IP fragments.
+pub const DEFAULT_PAYLOAD_SIZE: usize = 1500 - 20 - 8 - DATAGRAM_HEADER_SIZE;
+/// A missing datagram this far behind the newest one is counted as lost for
+/// good; if it still shows up it is taken for a duplicate.
+const REORDER_WINDOW: u64 = 65536;
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(value)
+}
+
+/// Receiver side bookkeeping of sequence numbers and transit times.
+#[derive(Default)]
+struct SequenceTracker {
+    next_seq: u64,
+    /// Skipped sequence numbers that may still arrive late.
+    missing: BTreeSet<u64>,
+    /// Missing datagrams given up on.
+    expired: u64,
+    /// Transit time of the previous datagram in microseconds, offset by
+    /// whatever the difference between the two clocks is.
+    prev_transit: Option<i64>,
+    stats: UdpStats,
+}
+
+impl SequenceTracker {
+    fn record(&mut self, seq: u64, sent_micros: u64, now_micros: u64) {
+        self.stats.received += 1;
+        if seq >= self.next_seq {
+            let gap = seq - self.next_seq;
+            if gap > REORDER_WINDOW {
+                self.expired += gap - REORDER_WINDOW;
+                self.next_seq = seq - REORDER_WINDOW;
+            }
+            self.missing.extend(self.next_seq..seq);
+            self.next_seq = seq + 1;
+            while let Some(&oldest) = self.missing.iter().next() {
+                if oldest + REORDER_WINDOW >= self.next_seq {
+                    break;
+                }
+                self.missing.remove(&oldest);
+                self.expired += 1;
+            }
+        } else if self.missing.remove(&seq) {
+            self.stats.out_of_order += 1;
+        } else {
+            self.stats.duplicates += 1;
+        }
+
+        // RFC 3550, section 6.4.1.
+        let transit = now_micros as i64 - sent_micros as i64;
+        if let Some(prev_transit) = self.prev_transit {
+            let d = (transit - prev_transit).abs() as f64 / 1e6;
+            self.stats.jitter += (d - self.stats.jitter) / 16.;
+        }
+        self.prev_transit = Some(transit);
+    }
+
+    fn stats(&self) -> UdpStats {
+        UdpStats {
+            lost: self.expired + self.missing.len() as u64,
+            ..self.stats
+        }
+    }
+}
+
+/// Kernel UDP socket sending buckets as numbered, timestamped datagrams of at
+/// most `MAX_DATAGRAM_SIZE` bytes, like iperf. An empty datagram marks the
+/// end of the test. Buckets count payload only, without the header.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    next_seq: u64,
+    datagram: Vec<u8>,
+    tracker: SequenceTracker,
+    stats: TransportStats,
+}
+
+impl UdpTransport {
+    /// Wraps a socket already connected to its peer.
+    pub fn new(socket: UdpSocket) -> io::Result<Self> {
+        socket.set_read_timeout(Some(END_TIMEOUT))?;
+        Ok(UdpTransport {
+            socket,
+            next_seq: 0,
+            datagram: vec![0; MAX_DATAGRAM_SIZE],
+            tracker: SequenceTracker::default(),
+            stats: TransportStats::default(),
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn connect(addr: &SocketAddr, _options: &TransportOptions) -> io::Result<Self> {
+        let socket = bind_udp_socket(&unspecified_addr(addr))?;
+        socket.connect(addr)?;
+        for _ in 0..MARKER_REPEAT {
+            socket.send(&STREAM_HELLO[..])?;
+        }
+        UdpTransport::new(socket)
+    }
+
+    fn send_bucket(&mut self, bucket: &[u8]) -> io::Result<()> {
+        for payload in bucket.chunks(MAX_PAYLOAD_SIZE) {
+            let size = DATAGRAM_HEADER_SIZE + payload.len();
+            self.datagram[..8].copy_from_slice(&self.next_seq.to_be_bytes()[..]);
+            self.datagram[8..16].copy_from_slice(&now_micros().to_be_bytes()[..]);
+            self.datagram[DATAGRAM_HEADER_SIZE..size].copy_from_slice(payload);
+            self.socket.send(&self.datagram[..size])?;
+            self.next_seq += 1;
+        }
+
+        self.stats.send_nbytes += bucket.len();
+        self.stats.send_nbuckets += 1;
+        Ok(())
+    }
+
+    /// Receives one datagram and returns its payload size. The payload is not
+    /// copied into `bucket`, as nothing looks at it.
+    fn recv_bucket(&mut self, _bucket: &mut [u8]) -> io::Result<Option<usize>> {
+        let nbytes = loop {
+            match self.socket.recv(&mut self.datagram[..]) {
+                Ok(nbytes) if self.datagram[..nbytes] == STREAM_HELLO[..] => continue,
+                Ok(nbytes) => break nbytes,
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    tracing::warn!(
+                        "no datagram for {:?}, assuming the peer finished",
+                        END_TIMEOUT
+                    );
+                    break 0;
+                }
+                // The peer closed its socket, possibly before its last end
+                // marker made it through.
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => break 0,
+                Err(e) => return Err(e),
+            }
+        };
+        if nbytes == 0 {
+            return Ok(None);
+        }
+        if nbytes < DATAGRAM_HEADER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("udp datagram of {} bytes has no header", nbytes),
+            ));
+        }
+        let seq = read_u64(&self.datagram[..8]);
+        let sent_micros = read_u64(&self.datagram[8..16]);
+        self.tracker.record(seq, sent_micros, now_micros());
+
+        let nbytes = nbytes - DATAGRAM_HEADER_SIZE;
+        self.stats.recv_nbytes += nbytes;
+        self.stats.recv_nbuckets += 1;
+        Ok(Some(nbytes))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        for _ in 0..MARKER_REPEAT {
+            match self.socket.send(&[]) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn Transport>, Box<dyn Transport>)> {
+        let other = UdpTransport::new(self.socket.try_clone()?)?;
+        Ok((self, Box::new(other)))
+    }
+
+    fn stats(&self) -> TransportStats {
+        let udp = if self.tracker.stats.received > 0 {
+            Some(self.tracker.stats())
+        } else {
+            None
+        };
+        TransportStats { udp, ..self.stats }
+    }
+}
+
+/// Waits on one UDP socket for the first peer to say hello and connects the
+/// socket to it.
+pub struct UdpTransportListener {
+    socket: Option<UdpSocket>,
+    local_addr: SocketAddr,
+    accept_timeout: Option<Duration>,
+}
+
+impl Listener for UdpTransportListener {
+    type Transport = UdpTransport;
+
+    fn bind(addr: &SocketAddr, options: &TransportOptions) -> io::Result<Self> {
+        let socket = bind_udp_socket(addr)?;
+        let local_addr = socket.local_addr()?;
+        Ok(UdpTransportListener {
+            socket: Some(socket),
+            local_addr,
+            accept_timeout: options.accept_timeout,
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn accept(&mut self) -> io::Result<UdpTransport> {
+        let socket = self.socket.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "udp listener serves a single peer",
+            )
+        })?;
+
+        let deadline = deadline(self.accept_timeout);
+        let mut datagram = vec![0; MAX_DATAGRAM_SIZE];
+        let peer_addr = loop {
+            check_deadline(deadline, "a udp stream hello")?;
+            // A zero timeout would be rejected rather than time out at once.
+            socket.set_read_timeout(deadline.map(|deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .max(Duration::from_millis(1))
+            }))?;
+            match socket.recv_from(&mut datagram) {
+                Ok((nbytes, peer_addr)) if datagram[..nbytes] == STREAM_HELLO[..] => {
+                    break peer_addr
+                }
+                Ok(_) => {}
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        };
+        let socket = self.socket.take().unwrap();
+        socket.connect(peer_addr)?;
+
+        UdpTransport::new(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_loss_reordering_and_duplicates() {
+        let mut tracker = SequenceTracker::default();
+        for &seq in &[0, 1, 3, 4, 2, 4, 7] {
+            tracker.record(seq, 1000, 2000);
+        }
+        let stats = tracker.stats();
+        assert_eq!(stats.received, 7);
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.duplicates, 1);
+        // 5 and 6 never arrived.
+        assert_eq!(stats.lost, 2);
+        assert_eq!(stats.jitter, 0.);
+    }
+
+    #[test]
+    fn accept_times_out_and_keeps_waiting_for_a_hello() {
+        let options = TransportOptions {
+            accept_timeout: Some(Duration::from_millis(50)),
+            ..TransportOptions::default()
+        };
+        let mut listener =
+            UdpTransportListener::bind(&"127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let err = listener.accept().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let addr = listener.local_addr().unwrap();
+        let _client = UdpTransport::connect(&addr, &TransportOptions::default()).unwrap();
+        assert!(listener.accept().is_ok());
+    }
+}
@@ -1,9 +1,61 @@
+use crate::proto::HostInfo;
 use nix::net::if_::InterfaceFlags;
-use nix::sys::socket::{AddressFamily, InetAddr, IpAddr, SockAddr};
+use nix::sys::socket::{AddressFamily, IpAddr, SockAddr};
+use nix::sys::utsname::uname;
 use smoltcp::wire::{IpAddress, IpCidr};
 use std::fs;
-use std::io;
-use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+
+pub fn host_info() -> HostInfo {
+    let uts = uname();
+    HostInfo {
+        hostname: uts.nodename().to_string(),
+        system: uts.sysname().to_string(),
+        release: uts.release().to_string(),
+        machine: uts.machine().to_string(),
+    }
+}
+
+/// Parses a bitrate in bits per second such as `2G`, `500M`, `64k` or
+/// `1000000`. Suffixes are decimal, as usual for network rates.
+pub fn parse_bitrate(bitrate: &str) -> Result<u64, String> {
+    let bitrate = bitrate.trim();
+    let (number, scale) = match bitrate.chars().last() {
+        Some('k') | Some('K') => (&bitrate[..bitrate.len() - 1], 1e3),
+        Some('m') | Some('M') => (&bitrate[..bitrate.len() - 1], 1e6),
+        Some('g') | Some('G') => (&bitrate[..bitrate.len() - 1], 1e9),
+        _ => (bitrate, 1.),
+    };
+    // Rates that round to zero bits per second are refused by the server.
+    match number.parse::<f64>().map(|number| (number * scale).round()) {
+        Ok(bits) if bits >= 1. && bits.is_finite() => Ok(bits as u64),
+        _ => Err(format!("bad bitrate {:?}", bitrate)),
+    }
+}
+
+/// Resolves a `host[:port]` address such as `myhost`, `myhost:5201`,
+/// `10.0.0.1` or `[::1]:5201`, using `default_port` if it has no port.
+pub fn resolve_address(address: &str, default_port: u16) -> Result<SocketAddr, String> {
+    if let Ok(addr) = address.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let bare = address.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = bare.parse::<std::net::IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => match port.parse::<u16>() {
+            Ok(port) => (host, port),
+            Err(_) => return Err(format!("bad port in address {:?}", address)),
+        },
+        None => (address, default_port),
+    };
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|err| format!("cannot resolve {:?}: {}", address, err))?
+        .next()
+        .ok_or_else(|| format!("{:?} has no addresses", address))
+}
 
 pub fn get_net_if_speed(device: &str) -> i32 {
     const DEFAULT_SPEED: i32 = 10000;
@@ -23,6 +75,33 @@ pub fn get_net_if_speed(device: &str) -> i32 {
     }
 }
 
+/// MAC address of `device` as the kernel prints it, e.g. `52:54:00:12:34:56`.
+pub fn get_net_if_mac(device: &str) -> Option<String> {
+    let address_path = format!("/sys/class/net/{}/address", device);
+    fs::read_to_string(address_path)
+        .ok()
+        .map(|address| address.trim().to_string())
+}
+
+/// Gateway of the IPv4 default route through `device`, from `/proc/net/route`.
+pub fn get_net_if_gateway(device: &str) -> Option<Ipv4Addr> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+    parse_default_gateway(&routes, device)
+}
+
+fn parse_default_gateway(routes: &str, device: &str) -> Option<Ipv4Addr> {
+    for line in routes.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[0] != device || fields[1] != "00000000" {
+            continue;
+        }
+        // Addresses are printed as hexadecimal in host byte order.
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        return Some(Ipv4Addr::from(gateway.to_ne_bytes()));
+    }
+    None
+}
+
 #[derive(Debug, Clone)]
 pub struct NCCLSocketDev {
     pub interface_name: String,
@@ -158,12 +237,8 @@ pub fn nix_if_addr_to_cidr(if_addr: &nix::ifaddrs::InterfaceAddress) -> IpCidr {
         }
     };
     let netmask: u32 = match netmask {
-        IpAddr::V4(v4) => {
-            v4.octets().iter().map(|x| x.count_ones()).sum()
-        }
-        IpAddr::V6(v6) => {
-            v6.segments().iter().map(|x| x.count_ones()).sum()
-        }
+        IpAddr::V4(v4) => v4.octets().iter().map(|x| x.count_ones()).sum(),
+        IpAddr::V6(v6) => v6.segments().iter().map(|x| x.count_ones()).sum(),
     };
 
     IpCidr::new(ip_addr, netmask as u8)
@@ -173,6 +248,46 @@ pub fn nix_if_addr_to_cidr(if_addr: &nix::ifaddrs::InterfaceAddress) -> IpCidr {
 mod tests {
     use super::*;
 
+    #[test]
+    fn parses_bitrates() {
+        assert_eq!(parse_bitrate("2G"), Ok(2_000_000_000));
+        assert_eq!(parse_bitrate("1.5m"), Ok(1_500_000));
+        assert_eq!(parse_bitrate("64000"), Ok(64000));
+        assert!(parse_bitrate("fast").is_err());
+        assert!(parse_bitrate("0").is_err());
+        assert!(parse_bitrate("0.0001k").is_err());
+        assert!(parse_bitrate("inf").is_err());
+    }
+
+    #[test]
+    fn resolves_addresses_with_default_port() {
+        let resolve = |address| resolve_address(address, 5201);
+        assert_eq!(resolve("10.0.0.1"), Ok("10.0.0.1:5201".parse().unwrap()));
+        assert_eq!(resolve("10.0.0.1:80"), Ok("10.0.0.1:80".parse().unwrap()));
+        assert_eq!(resolve("[::1]"), Ok("[::1]:5201".parse().unwrap()));
+        assert_eq!(resolve("[::1]:80"), Ok("[::1]:80".parse().unwrap()));
+        assert_eq!(resolve("localhost:80").unwrap().port(), 80);
+        assert!(resolve("localhost").unwrap().ip().is_loopback());
+        assert!(resolve("localhost:http").is_err());
+    }
+
+    #[test]
+    fn parses_default_gateway() {
+        // The kernel prints the big-endian address as a native integer.
+        let routes = format!(
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+             eth0\t00000000\t{:08X}\t0003\t0\t0\t100\t00000000\n\
+             eth0\t0000A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\n",
+            u32::from_ne_bytes([192, 168, 0, 1])
+        );
+        let routes = &routes[..];
+        assert_eq!(
+            parse_default_gateway(routes, "eth0"),
+            Some(Ipv4Addr::new(192, 168, 0, 1))
+        );
+        assert_eq!(parse_default_gateway(routes, "tap0"), None);
+    }
+
     #[test]
     fn it_works() {
         let sockdevs = find_interfaces();